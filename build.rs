@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(ossl300)");
+
+    // Exported by openssl-sys, used to enable APIs which need OpenSSL 3
+    if let Ok(version) = std::env::var("DEP_OPENSSL_VERSION_NUMBER") {
+        if u64::from_str_radix(&version, 16).is_ok_and(|version| version >= 0x3000_0000) {
+            println!("cargo:rustc-cfg=ossl300");
+        }
+    }
+}
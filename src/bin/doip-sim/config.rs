@@ -0,0 +1,227 @@
+use std::{error::Error, net::IpAddr, path::Path, time::Duration};
+
+use doip_sockets::tcp::{DiagnosticResponse, DOIP_TCP_PORT};
+use doip_sockets::udp::DOIP_UDP_DISCOVERY_PORT;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::hex;
+
+/// Simulated DoIP entity as read from the config file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimConfig {
+    /// Vehicle Identification Number, 17 ASCII characters
+    #[serde(deserialize_with = "vin")]
+    pub vin: [u8; 17],
+    /// Entity Identification, usually the MAC address
+    #[serde(deserialize_with = "hex_array")]
+    pub eid: [u8; 6],
+    /// Group Identification, defaults to the EID
+    #[serde(default, deserialize_with = "optional_hex_array")]
+    pub gid: Option<[u8; 6]>,
+    /// Logical address of the DoIP entity itself
+    #[serde(deserialize_with = "address")]
+    pub logical_address: [u8; 2],
+    /// Local address to listen on
+    #[serde(default = "default_bind")]
+    pub bind: IpAddr,
+    #[serde(default = "default_tcp_port")]
+    pub tcp_port: u16,
+    #[serde(default = "default_udp_port")]
+    pub udp_port: u16,
+    /// Maximum number of concurrently activated sockets
+    #[serde(default = "default_max_sockets")]
+    pub max_sockets: u8,
+    /// Maximum size of a Diagnostic Message reported in the entity status
+    #[serde(default = "default_max_data_size")]
+    pub max_data_size: u32,
+    /// Send Vehicle Announcement Messages on startup
+    #[serde(default)]
+    pub announce: bool,
+    /// ECUs reachable through the entity
+    #[serde(default, rename = "ecu")]
+    pub ecus: Vec<EcuConfig>,
+}
+
+/// Simulated ECU with its canned responses
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EcuConfig {
+    #[serde(deserialize_with = "address")]
+    pub logical_address: [u8; 2],
+    #[serde(default)]
+    pub responses: Vec<CannedResponse>,
+}
+
+/// UDS request and the response sent back for it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CannedResponse {
+    #[serde(deserialize_with = "hex_bytes")]
+    pub request: Vec<u8>,
+    #[serde(deserialize_with = "hex_bytes")]
+    pub response: Vec<u8>,
+    /// Time to wait before the response is sent in milliseconds
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Send a response pending right away when the response is delayed
+    #[serde(default)]
+    pub pending: bool,
+}
+
+impl SimConfig {
+    /// Reads the config from a TOML file
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read '{}': {}", path.display(), err))?;
+
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// Group Identification sent in Vehicle Announcement Messages
+    pub fn gid(&self) -> [u8; 6] {
+        self.gid.unwrap_or(self.eid)
+    }
+
+    /// Finds the simulated ECU with the given logical address
+    pub fn ecu(&self, address: [u8; 2]) -> Option<&EcuConfig> {
+        self.ecus.iter().find(|ecu| ecu.logical_address == address)
+    }
+}
+
+impl EcuConfig {
+    /// Looks up the responses for a UDS request
+    ///
+    /// Requests without an entry are answered with a negative response,
+    /// requestOutOfRange if other requests of the same service are configured
+    /// and serviceNotSupported otherwise.
+    pub fn respond(&self, request: &[u8]) -> Vec<DiagnosticResponse> {
+        let sid = request.first().copied().unwrap_or(0x00);
+
+        if let Some(canned) = self.responses.iter().find(|r| r.request == request) {
+            let response = DiagnosticResponse::new(canned.response.clone())
+                .after(Duration::from_millis(canned.delay_ms));
+
+            return if canned.pending {
+                vec![DiagnosticResponse::pending(sid), response]
+            } else {
+                vec![response]
+            };
+        }
+
+        let nrc = if self
+            .responses
+            .iter()
+            .any(|r| r.request.first() == Some(&sid))
+        {
+            0x31
+        } else {
+            0x11
+        };
+
+        vec![DiagnosticResponse::new(vec![0x7f, sid, nrc])]
+    }
+}
+
+fn default_bind() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+fn default_tcp_port() -> u16 {
+    DOIP_TCP_PORT
+}
+
+fn default_udp_port() -> u16 {
+    DOIP_UDP_DISCOVERY_PORT
+}
+
+fn default_max_sockets() -> u8 {
+    4
+}
+
+fn default_max_data_size() -> u32 {
+    4096
+}
+
+fn vin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 17], D::Error> {
+    let vin = String::deserialize(deserializer)?;
+
+    if !vin.is_ascii() {
+        return Err(de::Error::custom(format!("VIN '{}' is not ASCII", vin)));
+    }
+
+    vin.as_bytes()
+        .try_into()
+        .map_err(|_| de::Error::custom(format!("VIN '{}' must be 17 characters", vin)))
+}
+
+fn address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 2], D::Error> {
+    let address = String::deserialize(deserializer)?;
+    hex::parse_address(&address).map_err(de::Error::custom)
+}
+
+fn hex_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let bytes = String::deserialize(deserializer)?;
+    hex::parse_bytes(&bytes).map_err(de::Error::custom)
+}
+
+fn hex_array<'de, D: Deserializer<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<[u8; N], D::Error> {
+    hex_bytes(deserializer)?
+        .try_into()
+        .map_err(|_| de::Error::custom(format!("Expected {} hex bytes", N)))
+}
+
+fn optional_hex_array<'de, D: Deserializer<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<Option<[u8; N]>, D::Error> {
+    hex_array(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod test_config {
+    use std::time::Duration;
+
+    use super::SimConfig;
+
+    #[test]
+    fn test_example_config() {
+        let config: SimConfig = toml::from_str(include_str!("example.toml")).unwrap();
+
+        assert_eq!(&config.vin, b"WDD2221761A000001");
+        assert_eq!(config.logical_address, [0x10, 0x00]);
+        assert_eq!(config.gid(), config.eid);
+        assert_eq!(config.ecus.len(), 2);
+
+        let engine = config.ecu([0x10, 0x01]).unwrap();
+        let respond = |request: &[u8]| -> Vec<Vec<u8>> {
+            engine
+                .respond(request)
+                .into_iter()
+                .map(|res| res.message)
+                .collect()
+        };
+        assert_eq!(respond(&[0x3e, 0x00]), [vec![0x7e, 0x00]]);
+        assert_eq!(respond(&[0x22, 0x12, 0x34]), [vec![0x7f, 0x22, 0x31]]);
+        assert_eq!(respond(&[0x27, 0x01]), [vec![0x7f, 0x27, 0x11]]);
+        assert!(config.ecu([0x20, 0x00]).is_none());
+
+        let routine = engine.respond(&[0x31, 0x01, 0xff, 0x00]);
+        assert_eq!(routine.len(), 2);
+        assert_eq!(routine[0].message, vec![0x7f, 0x31, 0x78]);
+        assert_eq!(routine[1].delay, Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(toml::from_str::<SimConfig>(
+            "vin = \"SHORT\"\neid = \"00 11 22 33 44 55\"\nlogical_address = \"1000\""
+        )
+        .is_err());
+        assert!(toml::from_str::<SimConfig>(
+            "vin = \"WDD2221761A000001\"\neid = \"00 11\"\nlogical_address = \"1000\""
+        )
+        .is_err());
+    }
+}
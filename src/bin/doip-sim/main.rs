@@ -0,0 +1,227 @@
+//! `doip-sim` DoIP entity simulator driven by a config file.
+
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Parser;
+use doip_definitions::{
+    message::DoipMessage,
+    payload::{
+        ActionCode, AliveCheckResponse, DoipPayload, EntityStatusResponse, NodeType,
+        PowerInformationResponse, PowerMode, VehicleAnnouncementMessage,
+    },
+};
+use doip_sockets::{
+    tcp::{DiagnosticHandler, DiagnosticResponse, OpenSockets, TcpListener, TcpServer},
+    udp::{UdpSocket, UdpSocketHandle},
+};
+use futures::future::BoxFuture;
+use tokio_util::sync::CancellationToken;
+
+mod config;
+// Shared with the doip tool, which uses more of it
+#[allow(dead_code)]
+#[path = "../doip/hex.rs"]
+mod hex;
+
+use config::SimConfig;
+
+/// Number of Vehicle Announcement Messages sent on startup
+const ANNOUNCE_COUNT: usize = 3;
+/// Time between Vehicle Announcement Messages, A_DoIP_Announce_Interval
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Parser)]
+#[command(name = "doip-sim", version, about = "Simulated DoIP entity")]
+struct Cli {
+    /// TOML file describing the simulated entity and its ECUs
+    config: PathBuf,
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let config = Arc::new(SimConfig::load(&cli.config)?);
+
+    let udp = UdpSocket::bind(SocketAddr::new(config.bind, config.udp_port))
+        .await?
+        .into_handle();
+    udp.get_socket_ref().set_broadcast(true)?;
+
+    let listener =
+        tokio::net::TcpListener::bind(SocketAddr::new(config.bind, config.tcp_port)).await?;
+    let server = TcpServer::new(TcpListener::new(listener))
+        .logical_address(config.logical_address)
+        .max_sockets(config.max_sockets);
+
+    println!(
+        "Simulating {} at {} with {} ECUs on {}",
+        String::from_utf8_lossy(&config.vin),
+        hex::format_address(config.logical_address),
+        config.ecus.len(),
+        server.get_ref().get_ref().local_addr()?
+    );
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let _ = tokio::signal::ctrl_c().await;
+            shutdown.cancel();
+        }
+    });
+
+    if config.announce {
+        tokio::spawn(announce(udp.clone(), config.clone()));
+    }
+    let responder = tokio::spawn(respond_udp(udp, config.clone(), server.open_sockets()));
+
+    let handler = {
+        let config = config.clone();
+        move |_, msg: DoipMessage| {
+            let config = config.clone();
+            async move { handle_tcp(&config, msg.payload) }
+        }
+    };
+    let report = server
+        .diagnostic_handler(Arc::new(SimulatedEcus(config.clone())))
+        .run(Arc::new(handler), shutdown)
+        .await?;
+    responder.abort();
+
+    println!(
+        "Stopped, {} connections closed",
+        report.closed.len() + report.force_closed.len()
+    );
+
+    Ok(())
+}
+
+fn announcement(config: &SimConfig) -> DoipPayload {
+    DoipPayload::VehicleAnnouncementMessage(VehicleAnnouncementMessage {
+        vin: config.vin,
+        logical_address: config.logical_address,
+        eid: config.eid,
+        gid: config.gid(),
+        further_action: ActionCode::NoFurtherActionRequired,
+        vin_gid_sync: None,
+    })
+}
+
+/// Sends the Vehicle Announcement Messages after startup
+async fn announce(udp: UdpSocketHandle, config: Arc<SimConfig>) {
+    let addr = SocketAddr::new(Ipv4Addr::BROADCAST.into(), config.udp_port);
+
+    for _ in 0..ANNOUNCE_COUNT {
+        if let Err(err) = udp.send(announcement(&config), addr).await {
+            eprintln!("Failed to send announcement: {}", err);
+        }
+        tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+    }
+}
+
+/// Answers vehicle identification, entity status and power mode requests
+async fn respond_udp(udp: UdpSocketHandle, config: Arc<SimConfig>, sockets: OpenSockets) {
+    loop {
+        let (msg, peer) = match udp.recv().await {
+            Ok(msg) => msg,
+            Err(err) => {
+                eprintln!("Invalid datagram: {}", err);
+                continue;
+            }
+        };
+
+        let response = match msg.payload {
+            DoipPayload::VehicleIdentificationRequest(_) => Some(announcement(&config)),
+            DoipPayload::VehicleIdentificationRequestEid(req) if req.eid == config.eid => {
+                Some(announcement(&config))
+            }
+            DoipPayload::VehicleIdentificationRequestVin(req) if req.vin == config.vin => {
+                Some(announcement(&config))
+            }
+            DoipPayload::EntityStatusRequest(_) => {
+                let node_type = if config
+                    .ecus
+                    .iter()
+                    .any(|ecu| ecu.logical_address != config.logical_address)
+                {
+                    NodeType::DoipGateway
+                } else {
+                    NodeType::DoipNode
+                };
+
+                let open = u8::try_from(sockets.count()).unwrap_or(u8::MAX);
+
+                Some(DoipPayload::EntityStatusResponse(EntityStatusResponse {
+                    node_type,
+                    max_concurrent_sockets: [config.max_sockets],
+                    currently_open_sockets: [open],
+                    max_data_size: config.max_data_size.to_be_bytes(),
+                }))
+            }
+            DoipPayload::PowerInformationRequest(_) => Some(DoipPayload::PowerInformationResponse(
+                PowerInformationResponse {
+                    power_mode: PowerMode::Ready,
+                },
+            )),
+            _ => None,
+        };
+
+        if let Some(response) = response {
+            if let Err(err) = udp.send(response, peer).await {
+                eprintln!("Failed to respond to {}: {}", peer, err);
+            }
+        }
+    }
+}
+
+/// Answers Diagnostic Messages from the canned responses of the target ECU
+struct SimulatedEcus(Arc<SimConfig>);
+
+impl DiagnosticHandler for SimulatedEcus {
+    fn handle(
+        &self,
+        _source: [u8; 2],
+        target: [u8; 2],
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Vec<DiagnosticResponse>> {
+        let responses = self
+            .0
+            .ecu(target)
+            .map(|ecu| ecu.respond(&message))
+            .unwrap_or_default();
+
+        Box::pin(async move { responses })
+    }
+
+    fn has_target(&self, target: [u8; 2]) -> bool {
+        self.0.ecu(target).is_some()
+    }
+}
+
+/// Answers the remaining frames which are not handled by the server itself
+fn handle_tcp(config: &SimConfig, payload: DoipPayload) -> Vec<DoipPayload> {
+    match payload {
+        DoipPayload::AliveCheckRequest(_) => {
+            vec![DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                source_address: config.logical_address,
+            })]
+        }
+        _ => vec![],
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    error::Error,
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use doip_definitions::payload::{
+    AliveCheckResponse, DiagnosticMessage, DoipPayload, VehicleAnnouncementMessage,
+    VehicleIdentificationRequest,
+};
+use doip_sockets::{
+    tcp::{DoipConnection, RoutingActivation, TlsPolicy, DOIP_TCP_PORT, DOIP_TLS_PORT},
+    udp::{DiscoveryConfig, UdpSocket, DOIP_UDP_DISCOVERY_PORT},
+};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+
+use crate::{
+    hex::{format_address, format_bytes},
+    ConnectArgs,
+};
+
+/// Time to wait for the final response after a response pending, P2*_server
+const PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of a command in both output formats
+pub struct Output {
+    pub text: String,
+    pub json: Value,
+}
+
+pub async fn discover(
+    interfaces: Vec<String>,
+    target: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error>> {
+    let entities: Vec<(Option<String>, SocketAddr, VehicleAnnouncementMessage)> = match target {
+        Some(ip) => {
+            let addr = SocketAddr::new(ip, DOIP_UDP_DISCOVERY_PORT);
+            let mut socket = bind_for(ip).await?;
+            let announcement = socket
+                .request(VehicleIdentificationRequest {}, addr, timeout, 2)
+                .await?;
+
+            vec![(None, addr, announcement)]
+        }
+        None => {
+            let config = DiscoveryConfig {
+                interfaces: (!interfaces.is_empty()).then_some(interfaces),
+                timeout,
+                ..Default::default()
+            };
+
+            UdpSocket::discover(&config)
+                .await?
+                .into_iter()
+                .map(|entity| (Some(entity.interface), entity.addr, entity.announcement))
+                .collect()
+        }
+    };
+
+    let mut text = String::new();
+    let mut json = Vec::new();
+
+    if entities.is_empty() {
+        text.push_str("No DoIP entities found\n");
+    }
+
+    for (interface, addr, announcement) in entities {
+        let vin = String::from_utf8_lossy(&announcement.vin).into_owned();
+        let logical_address = format_address(announcement.logical_address);
+        let eid = format_bytes(&announcement.eid);
+        let gid = format_bytes(&announcement.gid);
+        let further_action = format!("{:?}", announcement.further_action);
+
+        let _ = writeln!(
+            text,
+            "{} VIN {} address {} EID {} GID {} further action {}{}",
+            addr,
+            vin,
+            logical_address,
+            eid,
+            gid,
+            further_action,
+            interface
+                .as_ref()
+                .map(|name| format!(" via {}", name))
+                .unwrap_or_default()
+        );
+
+        json.push(json!({
+            "interface": interface,
+            "addr": addr.to_string(),
+            "vin": vin,
+            "logical_address": logical_address,
+            "eid": eid,
+            "gid": gid,
+            "further_action": further_action,
+        }));
+    }
+
+    Ok(Output {
+        text,
+        json: Value::Array(json),
+    })
+}
+
+pub async fn status(ip: IpAddr) -> Result<Output, Box<dyn Error>> {
+    let addr = SocketAddr::new(ip, DOIP_UDP_DISCOVERY_PORT);
+    let mut socket = bind_for(ip).await?;
+
+    let status = socket.entity_status(addr).await?;
+    let power_mode = socket.power_mode(addr).await?;
+
+    let text = format!(
+        "node type {:?}\nsockets {}/{}\nmax data size {}\npower mode {:?}\n",
+        status.node_type,
+        status.currently_open_sockets,
+        status.max_concurrent_sockets,
+        status.max_data_size,
+        power_mode
+    );
+
+    let json = json!({
+        "node_type": format!("{:?}", status.node_type),
+        "max_concurrent_sockets": status.max_concurrent_sockets,
+        "currently_open_sockets": status.currently_open_sockets,
+        "max_data_size": status.max_data_size,
+        "power_mode": format!("{:?}", power_mode),
+    });
+
+    Ok(Output { text, json })
+}
+
+pub async fn activate(args: &ConnectArgs) -> Result<Output, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut conn = connect(args).await?;
+    let res = routing_activation(args).activate(&mut conn).await?;
+    let elapsed = start.elapsed().as_millis();
+
+    let text = format!(
+        "{:?} by {} for tester {} in {} ms{}\nReserved field {}\n",
+        res.activation_code,
+        format_address(res.source_address),
+        format_address(res.logical_address),
+        elapsed,
+        if conn.is_tls() { " over TLS" } else { "" },
+        format_bytes(&res.buffer)
+    );
+
+    let json = json!({
+        "activation_code": format!("{:?}", res.activation_code),
+        "entity_address": format_address(res.source_address),
+        "tester_address": format_address(res.logical_address),
+        "reserved": format_bytes(&res.buffer),
+        "tls": conn.is_tls(),
+        "time_ms": elapsed,
+    });
+
+    Ok(Output { text, json })
+}
+
+pub async fn send(
+    args: &ConnectArgs,
+    target: [u8; 2],
+    data: Vec<u8>,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error>> {
+    let mut conn = connect(args).await?;
+    routing_activation(args).activate(&mut conn).await?;
+
+    let events = exchange(&mut conn, args.tester, target, data, timeout).await?;
+
+    Ok(Output {
+        text: events.iter().map(|event| event.text() + "\n").collect(),
+        json: Value::Array(events.iter().map(Event::json).collect()),
+    })
+}
+
+/// Something received in response to a Diagnostic Message
+pub enum Event {
+    Ack { time_ms: u128 },
+    Nack { time_ms: u128, code: String },
+    Response { time_ms: u128, data: Vec<u8> },
+    Timeout { time_ms: u128 },
+}
+
+impl Event {
+    pub fn text(&self) -> String {
+        match self {
+            Event::Ack { time_ms } => format!("{:>6} ms  ack", time_ms),
+            Event::Nack { time_ms, code } => format!("{:>6} ms  nack {}", time_ms, code),
+            Event::Response { time_ms, data } => {
+                format!("{:>6} ms  response {}", time_ms, format_bytes(data))
+            }
+            Event::Timeout { time_ms } => format!("{:>6} ms  no response", time_ms),
+        }
+    }
+
+    pub fn json(&self) -> Value {
+        match self {
+            Event::Ack { time_ms } => json!({ "event": "ack", "time_ms": time_ms }),
+            Event::Nack { time_ms, code } => {
+                json!({ "event": "nack", "time_ms": time_ms, "code": code })
+            }
+            Event::Response { time_ms, data } => {
+                json!({ "event": "response", "time_ms": time_ms, "data": format_bytes(data) })
+            }
+            Event::Timeout { time_ms } => json!({ "event": "timeout", "time_ms": time_ms }),
+        }
+    }
+}
+
+/// Sends a UDS request and collects the acknowledgement and the responses of
+/// the target, following response pending until the final response
+pub async fn exchange(
+    conn: &mut DoipConnection,
+    tester: [u8; 2],
+    target: [u8; 2],
+    data: Vec<u8>,
+    timeout: Duration,
+) -> Result<Vec<Event>, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut deadline = start + timeout;
+    let mut events = Vec::new();
+
+    conn.send(DoipPayload::DiagnosticMessage(DiagnosticMessage {
+        source_address: tester,
+        target_address: target,
+        message: data,
+    }))
+    .await?;
+
+    loop {
+        let msg = match tokio::time::timeout_at(deadline, conn.read()).await {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Err("Connection closed by the DoIP entity".into()),
+            Err(_) => {
+                events.push(Event::Timeout {
+                    time_ms: start.elapsed().as_millis(),
+                });
+                break;
+            }
+        };
+        let time_ms = start.elapsed().as_millis();
+
+        match msg.payload {
+            DoipPayload::DiagnosticMessageAck(_) => events.push(Event::Ack { time_ms }),
+            DoipPayload::DiagnosticMessageNack(nack) => {
+                events.push(Event::Nack {
+                    time_ms,
+                    code: format!("{:?}", nack.nack_code),
+                });
+                break;
+            }
+            DoipPayload::DiagnosticMessage(res) if res.source_address == target => {
+                let pending = is_response_pending(&res.message);
+                events.push(Event::Response {
+                    time_ms,
+                    data: res.message,
+                });
+
+                if !pending {
+                    break;
+                }
+                deadline = Instant::now() + PENDING_TIMEOUT;
+            }
+            DoipPayload::AliveCheckRequest(_) => {
+                conn.send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                    source_address: tester,
+                }))
+                .await?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(events)
+}
+
+/// Whether the UDS response is a negative response with response pending
+fn is_response_pending(data: &[u8]) -> bool {
+    matches!(data, [0x7f, _, 0x78])
+}
+
+pub async fn connect(args: &ConnectArgs) -> Result<DoipConnection, Box<dyn Error>> {
+    let (policy, port) = if args.tls {
+        (TlsPolicy::TlsOnly, DOIP_TLS_PORT)
+    } else {
+        (TlsPolicy::PlainOnly, DOIP_TCP_PORT)
+    };
+    let addr = SocketAddr::new(args.ip, args.port.unwrap_or(port));
+
+    Ok(DoipConnection::connect_to(addr, addr, policy).await?)
+}
+
+pub fn routing_activation(args: &ConnectArgs) -> RoutingActivation {
+    let mut activation = RoutingActivation::new(args.tester);
+    activation.activation_type = args.activation_type.into();
+    activation.reserved = args.reserved.unwrap_or_default();
+    activation
+}
+
+async fn bind_for(ip: IpAddr) -> std::io::Result<UdpSocket> {
+    let local: IpAddr = match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    UdpSocket::bind(SocketAddr::new(local, 0)).await
+}
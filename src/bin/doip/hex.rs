@@ -0,0 +1,96 @@
+use std::fmt::Write;
+
+/// Parses hex bytes, whitespace between bytes is ignored
+pub fn parse_bytes(input: &str) -> Result<Vec<u8>, String> {
+    let digits: String = input.split_whitespace().collect();
+    let digits = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .unwrap_or(&digits);
+
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex digit '{}' in '{}'", c, input));
+    }
+
+    if digits.len() % 2 == 1 {
+        return Err(format!("Odd number of hex digits in '{}'", input));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("Invalid hex byte '{}'", &digits[i..i + 2]))
+        })
+        .collect()
+}
+
+/// Parses a logical address such as `0E80` or `0x0e80`
+pub fn parse_address(input: &str) -> Result<[u8; 2], String> {
+    let digits = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+        .unwrap_or(input);
+
+    u16::from_str_radix(digits, 16)
+        .map(u16::to_be_bytes)
+        .map_err(|_| format!("Invalid logical address '{}'", input))
+}
+
+/// Parses the 4 byte reserved field of a Routing Activation Request
+pub fn parse_reserved(input: &str) -> Result<[u8; 4], String> {
+    let bytes = parse_bytes(input)?;
+
+    bytes
+        .try_into()
+        .map_err(|_| format!("Reserved field '{}' must be 4 bytes", input))
+}
+
+/// Formats bytes as space separated upper case hex
+pub fn format_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        let _ = write!(out, "{:02X}", byte);
+    }
+
+    out
+}
+
+/// Formats a logical address as four hex digits
+pub fn format_address(address: [u8; 2]) -> String {
+    format!("{:04X}", u16::from_be_bytes(address))
+}
+
+#[cfg(test)]
+mod test_hex {
+    use super::{format_address, format_bytes, parse_address, parse_bytes, parse_reserved};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_bytes("22 F1 90").unwrap(), vec![0x22, 0xf1, 0x90]);
+        assert_eq!(parse_bytes("0x3e00").unwrap(), vec![0x3e, 0x00]);
+        assert!(parse_bytes("22F").is_err());
+        assert!(parse_bytes("zz").is_err());
+        assert!(parse_bytes("aéb").is_err());
+
+        assert_eq!(parse_address("0E80").unwrap(), [0x0e, 0x80]);
+        assert_eq!(parse_address("0x1001").unwrap(), [0x10, 0x01]);
+        assert!(parse_address("10001").is_err());
+
+        assert_eq!(
+            parse_reserved("de ad be ef").unwrap(),
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert!(parse_reserved("dead").is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_bytes(&[0x62, 0xf1, 0x90]), "62 F1 90");
+        assert_eq!(format_address([0x0e, 0x80]), "0E80");
+    }
+}
@@ -0,0 +1,177 @@
+//! `doip` command-line tool for poking DoIP entities on a test bench.
+
+use std::{error::Error, net::IpAddr, path::PathBuf, process::ExitCode, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use doip_definitions::payload::ActivationType;
+
+mod commands;
+mod hex;
+mod shell;
+
+use commands::Output;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "doip",
+    version,
+    about = "Diagnostics over IP command-line tool"
+)]
+struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Discover DoIP entities with a Vehicle Identification Request
+    Discover {
+        /// Only send on the named interfaces, may be repeated
+        #[arg(long = "interface", value_name = "NAME")]
+        interfaces: Vec<String>,
+        /// Ask a single entity instead of broadcasting
+        #[arg(long)]
+        target: Option<IpAddr>,
+        /// Time to wait for responses in milliseconds
+        #[arg(long, default_value_t = 2000)]
+        timeout_ms: u64,
+    },
+    /// Query the entity status and diagnostic power mode
+    Status {
+        /// Address of the DoIP entity
+        ip: IpAddr,
+    },
+    /// Test the Routing Activation
+    Activate {
+        #[command(flatten)]
+        conn: ConnectArgs,
+    },
+    /// Send a UDS request and print the responses
+    Send {
+        #[command(flatten)]
+        conn: ConnectArgs,
+        /// Logical address of the target ECU, e.g. 1001
+        #[arg(long, value_parser = hex::parse_address)]
+        target: [u8; 2],
+        /// Time to wait for a response in milliseconds
+        #[arg(long, default_value_t = 2000)]
+        timeout_ms: u64,
+        /// UDS request as hex bytes, e.g. 22 F1 90
+        #[arg(required = true)]
+        data: Vec<String>,
+    },
+    /// Open an interactive shell on an activated connection
+    Shell {
+        #[command(flatten)]
+        conn: ConnectArgs,
+        /// Logical address of the initial target ECU
+        #[arg(long, value_parser = hex::parse_address)]
+        target: Option<[u8; 2]>,
+        /// File to keep the command history in, defaults to ~/.doip_history
+        #[arg(long)]
+        history: Option<PathBuf>,
+    },
+}
+
+/// Options for commands which open a TCP connection
+#[derive(Debug, Clone, Args)]
+struct ConnectArgs {
+    /// Address of the DoIP entity
+    ip: IpAddr,
+    /// Connect with TLS
+    #[arg(long)]
+    tls: bool,
+    /// TCP port, defaults to 13400 or 3496 with --tls
+    #[arg(long)]
+    port: Option<u16>,
+    /// Logical address of the tester
+    #[arg(long, default_value = "0E80", value_parser = hex::parse_address)]
+    tester: [u8; 2],
+    /// Activation Type of the Routing Activation
+    #[arg(long, value_enum, default_value_t = ActivationTypeArg::Default)]
+    activation_type: ActivationTypeArg,
+    /// Reserved field of the Routing Activation Request as 4 hex bytes
+    #[arg(long, value_parser = hex::parse_reserved)]
+    reserved: Option<[u8; 4]>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ActivationTypeArg {
+    Default,
+    WwhObd,
+    CentralSecurity,
+}
+
+impl From<ActivationTypeArg> for ActivationType {
+    fn from(arg: ActivationTypeArg) -> Self {
+        match arg {
+            ActivationTypeArg::Default => ActivationType::Default,
+            ActivationTypeArg::WwhObd => ActivationType::WwhObd,
+            ActivationTypeArg::CentralSecurity => ActivationType::CentralSecurity,
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<Option<Output>, Box<dyn Error>> {
+    let output = match cli.command {
+        Command::Discover {
+            interfaces,
+            target,
+            timeout_ms,
+        } => commands::discover(interfaces, target, Duration::from_millis(timeout_ms)).await,
+        Command::Status { ip } => commands::status(ip).await,
+        Command::Activate { conn } => commands::activate(&conn).await,
+        Command::Send {
+            conn,
+            target,
+            timeout_ms,
+            data,
+        } => {
+            let data = hex::parse_bytes(&data.join(" "))?;
+            commands::send(&conn, target, data, Duration::from_millis(timeout_ms)).await
+        }
+        Command::Shell {
+            conn,
+            target,
+            history,
+        } => {
+            let history = history.or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".doip_history"))
+            });
+
+            shell::run(&conn, target, history, cli.json).await?;
+            return Ok(None);
+        }
+    };
+
+    output.map(Some)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let json = cli.json;
+
+    match run(cli).await {
+        Ok(output) => {
+            match output {
+                Some(output) if json => println!("{}", output.json),
+                Some(output) => print!("{}", output.text),
+                None => {}
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            if json {
+                println!("{}", serde_json::json!({ "error": err.to_string() }));
+            } else {
+                eprintln!("error: {}", err);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
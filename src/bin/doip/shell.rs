@@ -0,0 +1,232 @@
+use std::{error::Error, path::PathBuf, sync::mpsc as std_mpsc, time::Duration};
+
+use doip_definitions::payload::{AliveCheckResponse, DoipPayload};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use tokio::sync::mpsc;
+
+use crate::{
+    commands::{self, Event},
+    hex::{format_address, format_bytes, parse_address, parse_bytes},
+    ConnectArgs,
+};
+
+const HELP: &str = "\
+<hex>                    send a UDS request to the current target
+send <target> <hex>      send a UDS request to the given target
+target <address>         set the current target
+timeout <ms>             set the response timeout
+help                     show this help
+quit                     close the connection and exit
+";
+
+/// A line entered in the shell
+#[derive(Debug, PartialEq)]
+enum ShellCommand {
+    Empty,
+    Send {
+        target: Option<[u8; 2]>,
+        data: Vec<u8>,
+    },
+    Target([u8; 2]),
+    Timeout(Duration),
+    Help,
+    Quit,
+}
+
+fn parse_line(line: &str) -> Result<ShellCommand, String> {
+    let line = line.trim();
+    let (word, rest) = line
+        .split_once(char::is_whitespace)
+        .map(|(word, rest)| (word, rest.trim()))
+        .unwrap_or((line, ""));
+
+    match word {
+        "" => Ok(ShellCommand::Empty),
+        "help" | "?" => Ok(ShellCommand::Help),
+        "quit" | "exit" => Ok(ShellCommand::Quit),
+        "target" => parse_address(rest).map(ShellCommand::Target),
+        "timeout" => rest
+            .parse()
+            .map(|ms| ShellCommand::Timeout(Duration::from_millis(ms)))
+            .map_err(|_| format!("Invalid timeout '{}'", rest)),
+        "send" => {
+            let (target, data) = rest
+                .split_once(char::is_whitespace)
+                .ok_or("Usage: send <target> <hex>")?;
+
+            Ok(ShellCommand::Send {
+                target: Some(parse_address(target)?),
+                data: parse_bytes(data)?,
+            })
+        }
+        _ => Ok(ShellCommand::Send {
+            target: None,
+            data: parse_bytes(line)?,
+        }),
+    }
+}
+
+/// Runs the interactive shell on an activated connection
+///
+/// Lines are read on a separate thread so that Alive Check Requests are
+/// answered while the user is typing.
+pub async fn run(
+    args: &ConnectArgs,
+    target: Option<[u8; 2]>,
+    history: Option<PathBuf>,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = commands::connect(args).await?;
+    let res = commands::routing_activation(args)
+        .activate(&mut conn)
+        .await?;
+
+    println!(
+        "Connected to {}{}, type 'help' for commands",
+        format_address(res.source_address),
+        if conn.is_tls() { " over TLS" } else { "" }
+    );
+
+    let (lines_tx, mut lines) = mpsc::channel(1);
+    let (ready, ready_rx) = std_mpsc::channel();
+    std::thread::spawn(move || read_lines(history, lines_tx, ready_rx));
+
+    let mut target = target;
+    let mut timeout = Duration::from_secs(2);
+
+    loop {
+        tokio::select! {
+            line = lines.recv() => {
+                let Some(line) = line else { break };
+
+                match parse_line(&line) {
+                    Ok(ShellCommand::Empty) => {}
+                    Ok(ShellCommand::Help) => print!("{}", HELP),
+                    Ok(ShellCommand::Quit) => break,
+                    Ok(ShellCommand::Target(address)) => target = Some(address),
+                    Ok(ShellCommand::Timeout(value)) => timeout = value,
+                    Ok(ShellCommand::Send { target: to, data }) => match to.or(target) {
+                        // A failed exchange is reported but keeps the session, a
+                        // closed connection ends it on the next read
+                        Some(to) => {
+                            match commands::exchange(&mut conn, args.tester, to, data, timeout)
+                                .await
+                            {
+                                Ok(events) => print_events(&events, json),
+                                Err(err) => eprintln!("{}", err),
+                            }
+                        }
+                        None => eprintln!("No target set, use 'target <address>'"),
+                    },
+                    Err(err) => eprintln!("{}", err),
+                }
+
+                if ready.send(()).is_err() {
+                    break;
+                }
+            }
+            msg = conn.read() => match msg {
+                Some(Ok(msg)) => match msg.payload {
+                    DoipPayload::AliveCheckRequest(_) => {
+                        conn.send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                            source_address: args.tester,
+                        }))
+                        .await?;
+                    }
+                    DoipPayload::DiagnosticMessage(msg) => println!(
+                        "unsolicited from {}: {}",
+                        format_address(msg.source_address),
+                        format_bytes(&msg.message)
+                    ),
+                    _ => {}
+                },
+                Some(Err(err)) => return Err(err.into()),
+                None => return Err("Connection closed by the DoIP entity".into()),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn print_events(events: &[Event], json: bool) {
+    for event in events {
+        if json {
+            println!("{}", event.json());
+        } else {
+            println!("{}", event.text());
+        }
+    }
+}
+
+/// Reads lines with history until EOF, waiting for each line to be handled
+/// before showing the next prompt
+fn read_lines(
+    history: Option<PathBuf>,
+    lines: mpsc::Sender<String>,
+    ready: std_mpsc::Receiver<()>,
+) {
+    let Ok(mut editor) = DefaultEditor::new() else {
+        return;
+    };
+
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        match editor.readline("doip> ") {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                    if let Some(ref path) = history {
+                        let _ = editor.save_history(path);
+                    }
+                }
+
+                if lines.blocking_send(line).is_err() || ready.recv().is_err() {
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_shell {
+    use std::time::Duration;
+
+    use super::{parse_line, ShellCommand};
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("  ").unwrap(), ShellCommand::Empty);
+        assert_eq!(parse_line("quit").unwrap(), ShellCommand::Quit);
+        assert_eq!(
+            parse_line("target 1001").unwrap(),
+            ShellCommand::Target([0x10, 0x01])
+        );
+        assert_eq!(
+            parse_line("timeout 500").unwrap(),
+            ShellCommand::Timeout(Duration::from_millis(500))
+        );
+        assert_eq!(
+            parse_line("22 F1 90").unwrap(),
+            ShellCommand::Send {
+                target: None,
+                data: vec![0x22, 0xf1, 0x90],
+            }
+        );
+        assert_eq!(
+            parse_line("send 1002 10 03").unwrap(),
+            ShellCommand::Send {
+                target: Some([0x10, 0x02]),
+                data: vec![0x10, 0x03],
+            }
+        );
+        assert!(parse_line("send 1002").is_err());
+        assert!(parse_line("target xyz").is_err());
+    }
+}
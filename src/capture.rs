@@ -0,0 +1,593 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use doip_codec::DoipCodec;
+use doip_definitions::message::DoipMessage;
+use tokio_util::{bytes::BytesMut, codec::Encoder};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Largest TCP payload put into a single synthetic segment, larger frames are
+/// split as they would be on the wire
+const MAX_SEGMENT_SIZE: usize = 65_000;
+
+/// Records DoIP frames to a pcapng file
+///
+/// Frames are wrapped in synthetic Ethernet, IP and TCP or UDP headers built
+/// from the socket addresses, so the file can be opened with the DoIP dissector
+/// of Wireshark. Frames of TLS streams are recorded after decryption, as
+/// Wireshark expects TLS on port 3496 use "Decode As" DoIP for those.
+///
+/// A capture is cheap to clone and can be shared by several sockets. Frames
+/// are written synchronously through the writer, failures to write are ignored
+/// so that capturing never interrupts the traffic.
+#[derive(Clone)]
+pub struct Capture {
+    writer: Arc<Mutex<CaptureWriter>>,
+}
+
+struct CaptureWriter {
+    out: Box<dyn Write + Send>,
+    /// Next sequence number of each direction of a TCP connection
+    tcp_seq: HashMap<(SocketAddr, SocketAddr), u32>,
+}
+
+impl Capture {
+    /// Creates a new pcapng file, replacing an existing one
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_writer(BufWriter::new(File::create(path)?))
+    }
+
+    /// Writes the capture to any writer, e.g. a pipe to a live Wireshark
+    pub fn from_writer<W: Write + Send + 'static>(mut writer: W) -> io::Result<Self> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is not known up front
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, BLOCK_SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, BLOCK_INTERFACE_DESCRIPTION, &idb)?;
+
+        Ok(Capture {
+            writer: Arc::new(Mutex::new(CaptureWriter {
+                out: Box::new(writer),
+                tcp_seq: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Flush buffered frames to the underlying writer
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().out.flush()
+    }
+
+    pub(crate) fn record_tcp(&self, src: SocketAddr, dst: SocketAddr, msg: &DoipMessage) {
+        if let Some(frame) = encode(msg) {
+            self.record_tcp_bytes(src, dst, &frame)
+        }
+    }
+
+    /// Record bytes sent on a TCP stream, e.g. a frame corrupted on purpose
+    pub(crate) fn record_tcp_bytes(&self, src: SocketAddr, dst: SocketAddr, frame: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+
+        for chunk in frame.chunks(MAX_SEGMENT_SIZE) {
+            let seq = writer.tcp_seq.entry((src, dst)).or_insert(1);
+            let segment_seq = *seq;
+            *seq = seq.wrapping_add(chunk.len() as u32);
+            let ack = writer.tcp_seq.get(&(dst, src)).copied().unwrap_or(1);
+
+            let mut segment = Vec::with_capacity(20 + chunk.len());
+            segment.extend_from_slice(&src.port().to_be_bytes());
+            segment.extend_from_slice(&dst.port().to_be_bytes());
+            segment.extend_from_slice(&segment_seq.to_be_bytes());
+            segment.extend_from_slice(&ack.to_be_bytes());
+            // Data offset of 5 words, PSH and ACK set
+            segment.extend_from_slice(&[0x50, 0x18]);
+            segment.extend_from_slice(&u16::MAX.to_be_bytes());
+            // Checksum and urgent pointer, Wireshark does not validate the
+            // checksum by default
+            segment.extend_from_slice(&[0, 0, 0, 0]);
+            segment.extend_from_slice(chunk);
+
+            let _ = writer.write_packet(&packet(src, dst, PROTOCOL_TCP, &segment));
+        }
+    }
+
+    pub(crate) fn record_udp(&self, src: SocketAddr, dst: SocketAddr, msg: &DoipMessage) {
+        let Some(frame) = encode(msg) else { return };
+
+        let mut datagram = Vec::with_capacity(8 + frame.len());
+        datagram.extend_from_slice(&src.port().to_be_bytes());
+        datagram.extend_from_slice(&dst.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + frame.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(&frame);
+
+        let _ =
+            self.writer
+                .lock()
+                .unwrap()
+                .write_packet(&packet(src, dst, PROTOCOL_UDP, &datagram));
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+impl CaptureWriter {
+    fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_micros() as u64)
+            .unwrap_or_default();
+
+        let mut epb = Vec::with_capacity(20 + packet.len());
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(packet);
+
+        write_block(&mut self.out, BLOCK_ENHANCED_PACKET, &epb)
+    }
+}
+
+/// Addresses of a captured TCP stream
+#[derive(Debug, Clone)]
+pub(crate) struct StreamCapture {
+    capture: Capture,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl StreamCapture {
+    pub(crate) fn new(capture: Capture, local: SocketAddr, peer: SocketAddr) -> Self {
+        StreamCapture {
+            capture,
+            local,
+            peer,
+        }
+    }
+
+    pub(crate) fn sent(&self, msg: &DoipMessage) {
+        self.capture.record_tcp(self.local, self.peer, msg)
+    }
+
+    pub(crate) fn received(&self, msg: &DoipMessage) {
+        self.capture.record_tcp(self.peer, self.local, msg)
+    }
+
+    pub(crate) fn sent_raw(&self, bytes: &[u8]) {
+        self.capture.record_tcp_bytes(self.local, self.peer, bytes)
+    }
+}
+
+/// TCP segment or UDP datagram read back from a capture
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CapturedPacket {
+    /// Time since the Unix epoch
+    pub time: Duration,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Sequence number of a TCP segment, `None` for UDP
+    pub seq: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+/// Reads the TCP and UDP packets of a pcapng capture with Ethernet link type,
+/// other packets are skipped
+pub(crate) fn read_packets<R: Read>(mut reader: R) -> io::Result<Vec<CapturedPacket>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let mut packets = Vec::new();
+    let mut little_endian = true;
+    // Link type and timestamp units per second of each interface
+    let mut interfaces: Vec<(u16, u64)> = Vec::new();
+    let mut rest = &bytes[..];
+
+    while rest.len() >= 12 {
+        if rest[0..4] == BLOCK_SECTION_HEADER.to_le_bytes() {
+            little_endian = match rest[8..12].try_into().map(u32::from_le_bytes) {
+                Ok(BYTE_ORDER_MAGIC) => true,
+                Ok(magic) if magic.swap_bytes() == BYTE_ORDER_MAGIC => false,
+                _ => return Err(invalid_capture("Invalid byte order magic")),
+            };
+            interfaces.clear();
+        }
+
+        let read_u32 = |b: &[u8]| {
+            let b = b[..4].try_into().unwrap();
+            if little_endian {
+                u32::from_le_bytes(b)
+            } else {
+                u32::from_be_bytes(b)
+            }
+        };
+        let read_u16 = |b: &[u8]| {
+            let b = b[..2].try_into().unwrap();
+            if little_endian {
+                u16::from_le_bytes(b)
+            } else {
+                u16::from_be_bytes(b)
+            }
+        };
+
+        let block_type = read_u32(&rest[0..4]);
+        let len = read_u32(&rest[4..8]) as usize;
+        if len < 12 || len > rest.len() {
+            return Err(invalid_capture("Truncated block"));
+        }
+        let body = &rest[8..len - 4];
+        rest = &rest[len..];
+
+        match block_type {
+            BLOCK_INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                let mut units = 1_000_000;
+                let mut options = &body[8..];
+
+                while options.len() >= 4 {
+                    let code = read_u16(&options[0..2]);
+                    let len = read_u16(&options[2..4]) as usize;
+                    let value = options.get(4..4 + len).unwrap_or_default();
+
+                    // if_tsresol, a power of 10 or of 2 if the top bit is set
+                    if let (9, [resolution]) = (code, value) {
+                        units = match resolution & 0x80 {
+                            0 => 10u64.saturating_pow(u32::from(*resolution)),
+                            _ => 1u64 << (resolution & 0x7f).min(63),
+                        };
+                    }
+
+                    if code == 0 {
+                        break;
+                    }
+                    options = options
+                        .get(4 + len.next_multiple_of(4)..)
+                        .unwrap_or_default();
+                }
+
+                interfaces.push((read_u16(&body[0..2]), units));
+            }
+            BLOCK_ENHANCED_PACKET if body.len() >= 20 => {
+                let interface = interfaces.get(read_u32(&body[0..4]) as usize);
+                let Some(&(LINKTYPE_ETHERNET, units)) = interface else {
+                    continue;
+                };
+
+                let ts =
+                    (u64::from(read_u32(&body[4..8])) << 32) | u64::from(read_u32(&body[8..12]));
+                let time = Duration::from_nanos(
+                    (u128::from(ts) * 1_000_000_000 / u128::from(units)) as u64,
+                );
+
+                let captured = read_u32(&body[12..16]) as usize;
+                let Some(data) = body.get(20..20 + captured) else {
+                    return Err(invalid_capture("Truncated packet"));
+                };
+
+                if let Some(packet) = parse_packet(time, data) {
+                    packets.push(packet);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(packets)
+}
+
+fn invalid_capture(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses an Ethernet frame with a TCP segment or UDP datagram
+fn parse_packet(time: Duration, data: &[u8]) -> Option<CapturedPacket> {
+    let mut ethertype = u16::from_be_bytes(data.get(12..14)?.try_into().ok()?);
+    let mut ip = data.get(14..)?;
+
+    // VLAN tag
+    if ethertype == 0x8100 {
+        ethertype = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?);
+        ip = ip.get(4..)?;
+    }
+
+    let (src, dst, protocol, transport): (IpAddr, IpAddr, u8, &[u8]) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = usize::from(ip.first()? & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?));
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+
+            (
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                *ip.get(9)?,
+                ip.get(header_len..total_len)?,
+            )
+        }
+        ETHERTYPE_IPV6 => {
+            let payload_len = usize::from(u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?));
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                *ip.get(6)?,
+                ip.get(40..40 + payload_len)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let port = |offset: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            transport.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+
+    let (seq, payload) = match protocol {
+        PROTOCOL_TCP => {
+            let seq = u32::from_be_bytes(transport.get(4..8)?.try_into().ok()?);
+            let data_offset = usize::from(transport.get(12)? >> 4) * 4;
+            (Some(seq), transport.get(data_offset..)?)
+        }
+        PROTOCOL_UDP => (None, transport.get(8..usize::from(port(4)?))?),
+        _ => return None,
+    };
+
+    Some(CapturedPacket {
+        time,
+        src: SocketAddr::new(src, port(0)?),
+        dst: SocketAddr::new(dst, port(2)?),
+        seq,
+        payload: payload.to_vec(),
+    })
+}
+
+pub(crate) fn encode(msg: &DoipMessage) -> Option<BytesMut> {
+    let mut buf = BytesMut::new();
+    let mut codec = DoipCodec {};
+    codec.encode(msg.clone(), &mut buf).ok()?;
+    Some(buf)
+}
+
+fn write_block<W: Write + ?Sized>(out: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padded = body.len().next_multiple_of(4);
+    let total = (12 + padded) as u32;
+
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0; 3][..padded - body.len()])?;
+    out.write_all(&total.to_le_bytes())
+}
+
+/// Wraps a TCP segment or UDP datagram into Ethernet and IP headers
+fn packet(src: SocketAddr, dst: SocketAddr, protocol: u8, transport: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(54 + transport.len());
+    // Locally administered MAC addresses
+    packet.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    packet.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+            // Don't fragment
+            header[6] = 0x40;
+            header[8] = 64;
+            header[9] = protocol;
+            header[12..16].copy_from_slice(&src.octets());
+            header[16..20].copy_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            packet.extend_from_slice(&header);
+        }
+        (src, dst) => {
+            packet.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+            packet.push(protocol);
+            packet.push(64);
+            packet.extend_from_slice(&ipv6(src).octets());
+            packet.extend_from_slice(&ipv6(dst).octets());
+        }
+    }
+
+    packet.extend_from_slice(transport);
+    packet
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test_capture {
+    use std::net::SocketAddr;
+
+    use doip_definitions::{
+        builder::DoipMessageBuilder,
+        payload::{
+            ActivationType, AliveCheckRequest, AliveCheckResponse, DoipPayload,
+            RoutingActivationRequest,
+        },
+    };
+
+    use super::{ipv4_checksum, read_packets, Capture};
+    use crate::{tcp::TcpStream, test_util::SharedBuf};
+
+    /// Splits the file into blocks of type and body, without padding
+    fn blocks(bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        let mut rest = bytes;
+
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(&rest[len - 4..len], &rest[4..8]);
+            let mut body = rest[8..len - 4].to_vec();
+            if block_type == 6 {
+                // Drop the padding after the packet data
+                let captured = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+                body.truncate(20 + captured);
+            }
+            blocks.push((block_type, body));
+            rest = &rest[len..];
+        }
+
+        blocks
+    }
+
+    #[test]
+    fn test_tcp_packets() {
+        let buf = SharedBuf::default();
+        let capture = Capture::from_writer(buf.clone()).unwrap();
+
+        let tester: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let entity: SocketAddr = "10.0.0.2:13400".parse().unwrap();
+        let msg = DoipMessageBuilder::new()
+            .payload(DoipPayload::RoutingActivationRequest(
+                RoutingActivationRequest {
+                    source_address: [0x0e, 0x80],
+                    activation_type: ActivationType::Default,
+                    buffer: [0, 0, 0, 0],
+                },
+            ))
+            .build();
+
+        capture.record_tcp(tester, entity, &msg);
+        capture.record_tcp(tester, entity, &msg);
+
+        let blocks = blocks(&buf.0.lock().unwrap());
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].0, 0x0a0d_0d0a);
+        assert_eq!(blocks[1].0, 1);
+
+        let first = &blocks[2].1[20..];
+        let second = &blocks[3].1[20..];
+        let frame_len = first.len() - 54;
+
+        // IPv4 header with a valid checksum
+        assert_eq!(&first[12..14], &[0x08, 0x00]);
+        assert_eq!(ipv4_checksum(&first[14..34]), 0);
+        assert_eq!(&first[26..30], &[10, 0, 0, 1]);
+
+        // Ports and consecutive sequence numbers
+        assert_eq!(&first[34..36], &50000u16.to_be_bytes());
+        assert_eq!(&first[36..38], &13400u16.to_be_bytes());
+        let seq = |packet: &[u8]| u32::from_be_bytes(packet[38..42].try_into().unwrap());
+        assert_eq!(seq(second), seq(first) + frame_len as u32);
+
+        // Reading the capture back yields the original segments
+        let packets = read_packets(&buf.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!((packets[0].src, packets[0].dst), (tester, entity));
+        assert_eq!(packets[0].payload, &first[54..]);
+        assert_eq!(packets[1].seq, Some(seq(second)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_capture() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let buf = SharedBuf::default();
+        let mut tester = TcpStream::connect(addr).await.unwrap();
+        tester
+            .set_capture(Some(Capture::from_writer(buf.clone()).unwrap()))
+            .unwrap();
+        let mut entity = TcpStream::new(listener.accept().await.unwrap().0);
+
+        let _ = entity
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+        let _ = tester.read().await.unwrap().unwrap();
+        let _ = tester
+            .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                source_address: [0x0e, 0x80],
+            }))
+            .await;
+
+        let recorded = blocks(&buf.0.lock().unwrap());
+        assert_eq!(recorded.len(), 4);
+
+        // Received from the entity port, then sent to it
+        let port = |block: &Vec<u8>, offset: usize| {
+            u16::from_be_bytes(block[20 + offset..22 + offset].try_into().unwrap())
+        };
+        assert_eq!(port(&recorded[2].1, 34), addr.port());
+        assert_eq!(port(&recorded[3].1, 36), addr.port());
+        assert_eq!(recorded[3].1.len() - 20 - 54, 10);
+
+        // Split halves keep recording
+        let (mut read, mut write) = tester.into_split();
+        let _ = entity
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+        let _ = read.read().await.unwrap().unwrap();
+        let _ = write
+            .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                source_address: [0x0e, 0x80],
+            }))
+            .await;
+
+        let recorded = blocks(&buf.0.lock().unwrap());
+        assert_eq!(recorded.len(), 6);
+        assert_eq!(port(&recorded[4].1, 34), addr.port());
+        assert_eq!(port(&recorded[5].1, 36), addr.port());
+    }
+}
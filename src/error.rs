@@ -1,3 +1,6 @@
+use doip_definitions::payload::{ActivationCode, NackCode};
+
+/// Errors which can occur when sending a DoIP frame
 #[derive(thiserror::Error, Debug)]
 pub enum SocketSendError {
     /// Encode error from Codec
@@ -8,6 +11,139 @@ pub enum SocketSendError {
     #[error("Payload Type not supported by TCP Socket")]
     InvalidTcpPayload,
 
+    /// Diagnostic Message exceeds the maximum data size of the DoIP entity
+    #[error("Diagnostic Message of {size} bytes exceeds the maximum data size of {max} bytes")]
+    MessageTooLarge {
+        /// Size of the Diagnostic Message including source and target address
+        size: usize,
+        /// Maximum data size of the DoIP entity
+        max: u32,
+    },
+
+    /// IO Error
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Errors which can occur on a `ReconnectingStream`
+#[derive(thiserror::Error, Debug)]
+pub enum ReconnectError {
+    /// The connection was lost and has been re-established, any in-flight
+    /// requests must be repeated
+    #[error("Connection was lost and has been re-established")]
+    Reconnected,
+
+    /// Reconnection was abandoned after the configured number of attempts
+    #[error("Reconnection failed after {attempts} attempts: {source}")]
+    Exhausted {
+        /// Number of attempts made
+        attempts: u32,
+        /// Error of the last attempt
+        source: Box<ReconnectError>,
+    },
+
+    /// The DoIP entity refused the Routing Activation
+    #[error("Routing Activation denied: {0:?}")]
+    ActivationDenied(ActivationCode),
+
+    /// No Routing Activation Response was received in time
+    #[error("Routing Activation timed out")]
+    ActivationTimeout,
+
+    /// Send error from the underlying stream
+    #[error("Send Error: {0}")]
+    SendError(#[from] SocketSendError),
+
+    /// Decode error from Codec
+    #[error("Underlying Codec Error: {0}")]
+    DecodeError(#[from] doip_codec::Error),
+
+    /// IO Error
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+/// Errors which can occur during a Routing Activation
+#[derive(thiserror::Error, Debug)]
+pub enum ActivationError {
+    /// The DoIP entity refused the Routing Activation
+    #[error("Routing Activation denied: {0:?}")]
+    Denied(ActivationCode),
+
+    /// No Routing Activation Response was received in time
+    #[error("Routing Activation timed out")]
+    Timeout,
+
+    /// The activation was not confirmed before the confirmation timeout
+    #[error("Routing Activation was not confirmed in time")]
+    ConfirmationTimeout,
+
+    /// Send error from the underlying stream
+    #[error("Send Error: {0}")]
+    SendError(#[from] SocketSendError),
+
+    /// Decode error from Codec
+    #[error("Underlying Codec Error: {0}")]
+    DecodeError(#[from] doip_codec::Error),
+
+    /// IO Error
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+impl From<ActivationError> for ReconnectError {
+    fn from(err: ActivationError) -> Self {
+        match err {
+            ActivationError::Denied(code) => ReconnectError::ActivationDenied(code),
+            ActivationError::Timeout | ActivationError::ConfirmationTimeout => {
+                ReconnectError::ActivationTimeout
+            }
+            ActivationError::SendError(err) => ReconnectError::SendError(err),
+            ActivationError::DecodeError(err) => ReconnectError::DecodeError(err),
+            ActivationError::IoError(err) => ReconnectError::IoError(err),
+        }
+    }
+}
+
+/// Errors which can occur while replaying a recorded session
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayError {
+    /// Routing Activation with the recorded request failed
+    #[error("Routing Activation failed: {0}")]
+    Activation(#[from] ActivationError),
+
+    /// Send error from the underlying stream
+    #[error("Send Error: {0}")]
+    SendError(#[from] SocketSendError),
+
+    /// Decode error from Codec
+    #[error("Underlying Codec Error: {0}")]
+    DecodeError(#[from] doip_codec::Error),
+
+    /// The DoIP entity closed the connection
+    #[error("Connection closed by the DoIP entity")]
+    Closed,
+}
+
+/// Errors which can occur on a UDP request
+#[derive(thiserror::Error, Debug)]
+pub enum UdpRequestError {
+    /// The DoIP entity rejected the request with a Generic DoIP Header
+    /// Negative Acknowledge
+    #[error("Request rejected: {0:?}")]
+    Nack(NackCode),
+
+    /// No response was received after all attempts
+    #[error("No response after {attempts} attempts")]
+    Timeout {
+        /// Number of requests sent
+        attempts: u32,
+    },
+
+    /// Encode or decode error from Codec
+    #[error("Underlying Codec Error: {0}")]
+    CodecError(#[from] doip_codec::Error),
+
     /// IO Error
     #[error("IO Error: {0}")]
     IoError(#[from] std::io::Error),
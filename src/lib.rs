@@ -15,7 +15,11 @@
 //! however can be extended in future version.
 
 use doip_definitions::header::ProtocolVersion;
+mod capture;
 mod error;
+#[cfg(test)]
+mod test_util;
+mod trace;
 
 /// Simple TCP Stream and Split implentation for a TCP Stream allowing the conversion of a
 /// socket into a stream for Codec use, or the creating of a new TCP Stream
@@ -25,7 +29,9 @@ pub mod tcp;
 /// Simple UDP Socket implementation for UDP communication.
 pub mod udp;
 
+pub use capture::Capture;
 pub use doip_codec::Error;
+pub use error::{ActivationError, ReconnectError, ReplayError, SocketSendError, UdpRequestError};
 
 /// Configuration for UDP and TCP Sockets
 ///
@@ -34,4 +40,5 @@ pub use doip_codec::Error;
 #[derive(Debug, Copy, Clone)]
 pub struct SocketConfig {
     protocol_version: ProtocolVersion,
+    max_data_size: Option<u32>,
 }
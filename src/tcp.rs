@@ -1,22 +1,45 @@
+use std::future::Future;
+
+use doip_codec::Error as CodecError;
 use doip_definitions::{
     header::ProtocolVersion,
+    message::DoipMessage,
     payload::{
-        AliveCheckRequest, AliveCheckResponse, DiagnosticMessage, DiagnosticMessageAck,
-        DiagnosticMessageNack, GenericNack, RoutingActivationRequest, RoutingActivationResponse,
+        AliveCheckRequest, AliveCheckResponse, DiagnosticAckCode, DiagnosticMessage,
+        DiagnosticMessageAck, DiagnosticMessageNack, DoipPayload, GenericNack,
+        RoutingActivationRequest, RoutingActivationResponse,
     },
 };
 
-use crate::SocketConfig;
+use crate::{error::SocketSendError, SocketConfig};
 
+#[cfg(feature = "rustls")]
+mod rustls_stream;
 #[cfg(feature = "ssl")]
 mod ssl_stream;
+mod tcp_activation;
+mod tcp_connection;
 mod tcp_listener;
+mod tcp_mux;
+mod tcp_proxy;
+mod tcp_reconnect;
+mod tcp_replay;
+mod tcp_server;
 mod tcp_socket;
 mod tcp_split;
 mod tcp_stream;
+#[cfg(feature = "rustls")]
+pub use crate::tcp::rustls_stream::*;
 #[cfg(feature = "ssl")]
 pub use crate::tcp::ssl_stream::*;
+pub use crate::tcp::tcp_activation::*;
+pub use crate::tcp::tcp_connection::*;
 pub use crate::tcp::tcp_listener::*;
+pub use crate::tcp::tcp_mux::*;
+pub use crate::tcp::tcp_proxy::*;
+pub use crate::tcp::tcp_reconnect::*;
+pub use crate::tcp::tcp_replay::*;
+pub use crate::tcp::tcp_server::*;
 pub use crate::tcp::tcp_socket::*;
 pub use crate::tcp::tcp_split::*;
 pub use crate::tcp::tcp_stream::*;
@@ -34,10 +57,68 @@ impl DoipTcpPayload for DiagnosticMessage {}
 impl DoipTcpPayload for DiagnosticMessageAck {}
 impl DoipTcpPayload for DiagnosticMessageNack {}
 
+/// Common interface of the framed DoIP TCP Streams
+///
+/// Implemented by both `TcpStream` and `DoIpSslStream` so that helpers can be
+/// written once for plain and TLS connections.
+pub trait DoipStream: Send {
+    /// Send a DoIP frame to the sink
+    fn send(
+        &mut self,
+        payload: DoipPayload,
+    ) -> impl Future<Output = Result<(), SocketSendError>> + Send;
+
+    /// Read a DoIP frame off the stream
+    fn read(&mut self) -> impl Future<Output = Option<Result<DoipMessage, CodecError>>> + Send;
+}
+
+/// Positive acknowledgement of a received Diagnostic Message
+pub(crate) fn diagnostic_ack(req: &DiagnosticMessage) -> DoipPayload {
+    DoipPayload::DiagnosticMessageAck(DiagnosticMessageAck {
+        source_address: req.target_address,
+        target_address: req.source_address,
+        ack_code: DiagnosticAckCode::Acknowledged,
+        previous_message: req.message.clone(),
+    })
+}
+
+/// Length of the frame at the start of the buffer which the codec failed to
+/// decode
+///
+/// Without a valid protocol version pattern the frame cannot be delimited, so
+/// it spans the whole buffer.
+pub(crate) fn invalid_frame_len(buf: &[u8]) -> usize {
+    match buf {
+        [version, inverse, _, _, len @ ..] if *inverse == !*version && len.len() >= 4 => {
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+            8usize.saturating_add(len as usize)
+        }
+        _ => buf.len(),
+    }
+}
+
 impl Default for SocketConfig {
     fn default() -> Self {
         Self {
             protocol_version: ProtocolVersion::DefaultValue,
+            max_data_size: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    /// Rejects Diagnostic Messages which exceed the maximum data size of the
+    /// DoIP entity
+    pub(crate) fn check_size(&self, payload: &DoipPayload) -> Result<(), SocketSendError> {
+        if let (Some(max), DoipPayload::DiagnosticMessage(msg)) = (self.max_data_size, payload) {
+            // Source and target address count towards the data size
+            let size = msg.message.len() + 4;
+
+            if size > max as usize {
+                return Err(SocketSendError::MessageTooLarge { size, max });
+            }
         }
+
+        Ok(())
     }
 }
@@ -0,0 +1,500 @@
+use std::{
+    io::{self},
+    sync::Arc,
+};
+
+use doip_codec::{DoipCodec, Error as CodecError};
+use doip_definitions::{
+    builder::DoipMessageBuilder, header::ProtocolVersion, message::DoipMessage,
+    payload::DoipPayload,
+};
+use futures::{SinkExt, StreamExt};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio::net::{TcpStream as TokioTcpStream, ToSocketAddrs};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+use crate::{error::SocketSendError, trace::FrameTrace};
+
+use super::{
+    diagnostic_ack,
+    tcp_split::{TcpStreamReadHalf, TcpStreamWriteHalf},
+    DoipStream, SocketConfig,
+};
+
+/// Cipher suites used by `DoIpTlsStream::connect`, matching the defaults of
+/// `DoIpSslStream::connect`
+pub const DEFAULT_TLS_CIPHER_SUITES: &[&str] = &[
+    "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+    "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+    "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+    "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+    "TLS13_AES_128_GCM_SHA256",
+    "TLS13_AES_256_GCM_SHA384",
+];
+
+/// How the certificate presented by the DoIP entity is validated
+#[derive(Debug, Clone)]
+pub enum TlsVerification {
+    /// Accept any certificate, as done by `DoIpSslStream`
+    ///
+    /// Only intended for development benches where ECUs present self-signed
+    /// certificates.
+    Disabled,
+    /// Validate the certificate chain against the given trust anchors
+    RootStore(Arc<RootCertStore>),
+}
+
+/// Builds a rustls client config restricted to the given cipher suites
+///
+/// Cipher suites are given by their IANA names, e.g.
+/// `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`. Unknown names are rejected. Note
+/// that rustls does not support NULL ciphers, use the `ssl` feature for those.
+pub fn tls_client_config(
+    cipher_suites: &[&str],
+    verification: TlsVerification,
+) -> io::Result<ClientConfig> {
+    let provider = Arc::new(crypto_provider(cipher_suites)?);
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let config = match verification {
+        TlsVerification::Disabled => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth(),
+        TlsVerification::RootStore(roots) => {
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+
+    Ok(config)
+}
+
+/// Builds a rustls server config restricted to the given cipher suites which
+/// presents the certificate chain, e.g. for a simulated DoIP entity
+///
+/// The chain starts with the certificate of the entity, followed by the
+/// intermediates. Client certificates are not requested.
+pub fn tls_server_config(
+    cipher_suites: &[&str],
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<ServerConfig> {
+    let provider = Arc::new(crypto_provider(cipher_suites)?);
+
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn crypto_provider(cipher_suites: &[&str]) -> io::Result<CryptoProvider> {
+    let default = ring::default_provider();
+
+    let suites = cipher_suites
+        .iter()
+        .map(|name| {
+            default
+                .cipher_suites
+                .iter()
+                .find(|suite| suite.suite().as_str() == Some(*name))
+                .copied()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unsupported cipher suite: {}", name),
+                    )
+                })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(CryptoProvider {
+        cipher_suites: suites,
+        ..default
+    })
+}
+
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Simple implementation of a TLS Stream backed by rustls
+///
+/// Pure-Rust alternative to `DoIpSslStream` which offers the same methods and
+/// can be used for both the client and the server side of a connection.
+#[derive(Debug)]
+pub struct DoIpTlsStream {
+    io: Framed<TlsStream<TokioTcpStream>, DoipCodec>,
+    config: SocketConfig,
+    trace: FrameTrace,
+}
+
+impl DoIpTlsStream {
+    /// Creates a new TLS Stream from an established rustls TLS Stream
+    pub fn new(io: TlsStream<TokioTcpStream>) -> Self {
+        DoIpTlsStream {
+            trace: FrameTrace::tcp(io.get_ref().0.peer_addr().ok(), true),
+            io: Framed::new(io, DoipCodec {}),
+            config: SocketConfig {
+                protocol_version: ProtocolVersion::Iso13400_2012,
+                max_data_size: None,
+            },
+        }
+    }
+
+    /// Creates a new TLS Stream given a remote address with the default
+    /// cipher suites, validating the certificate of the entity against the
+    /// trust anchors
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+        server_name: ServerName<'static>,
+        roots: Arc<RootCertStore>,
+    ) -> io::Result<DoIpTlsStream> {
+        let config =
+            tls_client_config(DEFAULT_TLS_CIPHER_SUITES, TlsVerification::RootStore(roots))?;
+
+        Self::connect_with_config(addr, server_name, Arc::new(config)).await
+    }
+
+    /// Creates a new TLS Stream given a remote address with the default
+    /// cipher suites and without certificate validation
+    ///
+    /// Only intended for development benches, see `TlsVerification::Disabled`.
+    pub async fn connect_without_verification<A: ToSocketAddrs>(
+        addr: A,
+    ) -> io::Result<DoIpTlsStream> {
+        let stream = TokioTcpStream::connect(addr).await?;
+
+        Self::handshake_without_verification(stream).await
+    }
+
+    /// Performs the client side TLS handshake with the default cipher suites
+    /// and without certificate validation on an established TCP connection
+    pub(crate) async fn handshake_without_verification(
+        stream: TokioTcpStream,
+    ) -> io::Result<DoIpTlsStream> {
+        let config = tls_client_config(DEFAULT_TLS_CIPHER_SUITES, TlsVerification::Disabled)?;
+
+        // The server name is not checked without validation, but rustls
+        // requires one to be set
+        let server_name = ServerName::try_from("doip-entity").map_err(io::Error::other)?;
+
+        Self::handshake(stream, server_name, Arc::new(config)).await
+    }
+
+    /// Creates a new TLS Stream given a remote address and a rustls client
+    /// config, see `tls_client_config`
+    pub async fn connect_with_config<A: ToSocketAddrs>(
+        addr: A,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<DoIpTlsStream> {
+        let stream = TokioTcpStream::connect(addr).await?;
+
+        Self::handshake(stream, server_name, config).await
+    }
+
+    async fn handshake(
+        stream: TokioTcpStream,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> io::Result<DoIpTlsStream> {
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+
+        Ok(Self::new(TlsStream::Client(stream)))
+    }
+
+    /// Performs the server side TLS handshake on an accepted TCP connection
+    pub async fn accept(
+        stream: TokioTcpStream,
+        config: Arc<ServerConfig>,
+    ) -> io::Result<DoIpTlsStream> {
+        let stream = TlsAcceptor::from(config).accept(stream).await?;
+
+        Ok(Self::new(TlsStream::Server(stream)))
+    }
+
+    /// Send a DoIP frame to the sink
+    pub async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        self.config.check_size(&payload)?;
+
+        let msg = DoipMessageBuilder::new()
+            .protocol_version(self.config.protocol_version)
+            .payload(payload)
+            .build();
+        let sent = self.trace.enabled().then(|| msg.clone());
+
+        match self.io.send(msg).await {
+            Ok(_) => {
+                if let Some(msg) = sent {
+                    self.trace.sent(&msg);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.trace.error("Failed to send frame", &err);
+                Err(SocketSendError::EncodeError(err))
+            }
+        }
+    }
+
+    /// Read a DoIP frame off the stream
+    pub async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        let res = self.io.next().await;
+        match res {
+            Some(Ok(ref msg)) => self.trace.received(msg),
+            Some(Err(ref err)) => self.trace.error("Failed to read frame", err),
+            None => self.trace.closed(),
+        }
+        if let Some(Ok(ref msg)) = res {
+            if let DoipPayload::DiagnosticMessage(ref diag_msg) = msg.payload {
+                if let Err(e) = self.send(diagnostic_ack(diag_msg)).await {
+                    self.trace.error("Failed to send DiagnosticMessageAck", &e);
+                    return Some(Err(CodecError::IoError(io::Error::other(format!(
+                        "Failed to send DiagnosticMessageAck: {}",
+                        e
+                    )))));
+                }
+            }
+        }
+        res
+    }
+
+    /// Limit the size of outgoing Diagnostic Messages, e.g. to the maximum data
+    /// size reported in the entity status of the DoIP entity
+    pub fn set_max_data_size(&mut self, max: Option<u32>) {
+        self.config.max_data_size = max
+    }
+
+    /// Splits the TLS Stream into a Read Half and Write Half
+    pub fn into_split(
+        self,
+    ) -> (
+        TcpStreamReadHalf<TlsStream<TokioTcpStream>>,
+        TcpStreamWriteHalf<TlsStream<TokioTcpStream>>,
+    ) {
+        let stream: TlsStream<TokioTcpStream> = self.io.into_inner();
+
+        let (r_half, w_half) = tokio::io::split(stream);
+
+        let read = FramedRead::new(r_half, DoipCodec {});
+        let write = FramedWrite::new(w_half, DoipCodec {});
+
+        (
+            TcpStreamReadHalf::new(read, Some(self.config)).traced(self.trace.clone()),
+            TcpStreamWriteHalf::new(write, Some(self.config)).traced(self.trace),
+        )
+    }
+
+    /// Get a reference to the inner rustls TLS Stream
+    pub fn get_stream_ref(&self) -> &TlsStream<TokioTcpStream> {
+        self.io.get_ref()
+    }
+
+    /// Access the inner rustls TLS Stream, consumes the DoIP TLS Stream
+    pub fn into_socket(self) -> TlsStream<TokioTcpStream> {
+        self.io.into_inner()
+    }
+}
+
+impl DoipStream for DoIpTlsStream {
+    async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        DoIpTlsStream::send(self, payload).await
+    }
+
+    async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        DoIpTlsStream::read(self).await
+    }
+}
+
+#[cfg(test)]
+mod test_rustls_stream {
+    use std::sync::Arc;
+
+    use doip_definitions::payload::{AliveCheckRequest, AliveCheckResponse, DoipPayload};
+    use rustls::{
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        RootCertStore,
+    };
+
+    use crate::tcp::rustls_stream::{
+        tls_client_config, tls_server_config, DoIpTlsStream, TlsVerification,
+        DEFAULT_TLS_CIPHER_SUITES,
+    };
+
+    #[test]
+    fn test_client_config_cipher_suites() {
+        let config = tls_client_config(
+            &["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"],
+            TlsVerification::Disabled,
+        )
+        .unwrap();
+
+        assert_eq!(config.crypto_provider().cipher_suites.len(), 1);
+    }
+
+    #[test]
+    fn test_client_config_rejects_unknown_cipher() {
+        let config = tls_client_config(&["NULL-SHA256"], TlsVerification::Disabled);
+
+        assert!(config.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        const ENTITY_ADDR: &str = "127.0.0.1:0";
+
+        let cert = rcgen::generate_simple_self_signed(vec!["doip-entity".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+        let server_config = tls_server_config(
+            DEFAULT_TLS_CIPHER_SUITES,
+            vec![cert.cert.der().clone()],
+            key,
+        )
+        .unwrap();
+
+        let listener = tokio::net::TcpListener::bind(ENTITY_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let entity = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut entity = DoIpTlsStream::accept(stream, Arc::new(server_config))
+                .await
+                .unwrap();
+
+            let req = entity.read().await.unwrap().unwrap();
+            assert_eq!(
+                req.payload,
+                DoipPayload::AliveCheckRequest(AliveCheckRequest {})
+            );
+            let _ = entity
+                .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                    source_address: [0x10, 0x00],
+                }))
+                .await;
+        });
+
+        // The self-signed certificate is validated as trust anchor
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_config = tls_client_config(
+            DEFAULT_TLS_CIPHER_SUITES,
+            TlsVerification::RootStore(Arc::new(roots)),
+        )
+        .unwrap();
+
+        let mut tester = DoIpTlsStream::connect_with_config(
+            addr,
+            ServerName::try_from("doip-entity").unwrap(),
+            Arc::new(client_config),
+        )
+        .await
+        .unwrap();
+
+        let _ = tester
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+        let res = tester.read().await.unwrap().unwrap();
+        assert_eq!(
+            res.payload,
+            DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                source_address: [0x10, 0x00],
+            })
+        );
+
+        entity.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_unknown_certificate() {
+        const ENTITY_ADDR: &str = "127.0.0.1:0";
+
+        let cert = rcgen::generate_simple_self_signed(vec!["doip-entity".to_string()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+        let server_config = tls_server_config(
+            DEFAULT_TLS_CIPHER_SUITES,
+            vec![cert.cert.der().clone()],
+            key,
+        )
+        .unwrap();
+
+        let listener = tokio::net::TcpListener::bind(ENTITY_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = DoIpTlsStream::accept(stream, Arc::new(server_config)).await;
+        });
+
+        // Validating against an empty trust store fails the handshake
+        let client_config = tls_client_config(
+            DEFAULT_TLS_CIPHER_SUITES,
+            TlsVerification::RootStore(Arc::new(RootCertStore::empty())),
+        )
+        .unwrap();
+
+        let tester = DoIpTlsStream::connect_with_config(
+            addr,
+            ServerName::try_from("doip-entity").unwrap(),
+            Arc::new(client_config),
+        )
+        .await;
+        assert!(tester.is_err());
+    }
+}
@@ -1,28 +1,168 @@
 use std::{
-    io::{self},
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    net::SocketAddr,
     pin::Pin,
+    sync::{Arc, Mutex},
 };
 
 use doip_codec::{DoipCodec, Error as CodecError};
 use doip_definitions::{
-    builder::DoipMessageBuilder,
-    header::ProtocolVersion,
-    message::DoipMessage,
-    payload::{DiagnosticAckCode, DiagnosticMessageAck, DoipPayload},
+    builder::DoipMessageBuilder, header::ProtocolVersion, message::DoipMessage,
+    payload::DoipPayload,
 };
 use futures::{SinkExt, StreamExt};
-use openssl::ssl::{Ssl, SslContextBuilder, SslMethod, SslOptions, SslVerifyMode, SslVersion};
+use openssl::{
+    ex_data::Index,
+    ssl::{
+        NameType, Ssl, SslContext, SslContextBuilder, SslMethod, SslOptions, SslRef, SslSession,
+        SslSessionCacheMode, SslVerifyMode, SslVersion,
+    },
+};
 use tokio::net::{TcpStream as TokioTcpStream, ToSocketAddrs};
 
 use tokio_openssl::SslStream;
 use tokio_util::codec::{Framed, FramedRead, FramedWrite};
 
-use crate::error::SocketSendError;
+use crate::{
+    capture::{Capture, StreamCapture},
+    error::SocketSendError,
+    trace::FrameTrace,
+};
 
 use super::{
+    diagnostic_ack,
     tcp_split::{TcpStreamReadHalf, TcpStreamWriteHalf},
-    SocketConfig,
+    DoipStream, SocketConfig,
 };
+/// Sink for TLS session keys in the SSLKEYLOGFILE format
+///
+/// Attach a key log to a connection to decrypt captured traffic with Wireshark.
+/// This exposes the session secrets and should only be used on development
+/// benches.
+#[derive(Clone)]
+pub struct KeyLog {
+    sink: Arc<dyn Fn(&str) + Send + Sync>,
+}
+
+impl KeyLog {
+    /// Creates a key log which passes each line to the callback
+    pub fn from_callback<F>(callback: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        KeyLog {
+            sink: Arc::new(callback),
+        }
+    }
+
+    /// Creates a key log which appends each line to the writer
+    pub fn from_writer<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let writer = Mutex::new(writer);
+
+        Self::from_callback(move |line| {
+            let mut writer = writer.lock().unwrap();
+            let _ = writeln!(writer, "{}", line);
+            let _ = writer.flush();
+        })
+    }
+
+    /// Creates a key log appending to the file named by the `SSLKEYLOGFILE`
+    /// environment variable, returns `None` if the variable is not set
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match std::env::var_os("SSLKEYLOGFILE") {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+
+                Ok(Some(Self::from_writer(file)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn log(&self, line: &str) {
+        (self.sink)(line)
+    }
+}
+
+impl fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyLog").finish_non_exhaustive()
+    }
+}
+
+const DEFAULT_CLIENT_CIPHERS: &[&str] = &[
+    "ECDHE-RSA-AES128-GCM-SHA256",
+    "ECDHE-RSA-AES256-GCM-SHA384",
+    "ECDHE-ECDSA-AES128-GCM-SHA256",
+    "ECDHE-ECDSA-AES256-GCM-SHA384",
+];
+
+/// Parameters negotiated during the TLS handshake
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    /// Protocol version, e.g. `TLSv1.3`
+    pub version: String,
+    /// Cipher in openssl format, e.g. `ECDHE-RSA-AES128-GCM-SHA256`
+    pub cipher: Option<String>,
+    /// Key exchange group, e.g. `prime256v1`, requires OpenSSL 3
+    pub group: Option<String>,
+    /// Certificate chain presented by the peer in DER, leaf first
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// Server name sent with the SNI extension
+    pub server_name: Option<String>,
+}
+
+impl TlsInfo {
+    fn from_ssl(ssl: &SslRef) -> Self {
+        let peer_certificates = match ssl.peer_cert_chain() {
+            Some(chain) => chain.iter().filter_map(|cert| cert.to_der().ok()).collect(),
+            None => ssl
+                .peer_certificate()
+                .and_then(|cert| cert.to_der().ok())
+                .into_iter()
+                .collect(),
+        };
+
+        TlsInfo {
+            version: ssl.version_str().to_owned(),
+            cipher: ssl.current_cipher().map(|cipher| cipher.name().to_owned()),
+            group: negotiated_group(ssl),
+            peer_certificates,
+            server_name: ssl.servername(NameType::HOST_NAME).map(str::to_owned),
+        }
+    }
+}
+
+#[cfg(ossl300)]
+fn negotiated_group(ssl: &SslRef) -> Option<String> {
+    use openssl::pkey::Id;
+
+    let key = ssl.peer_tmp_key().ok()?;
+
+    match key.id() {
+        Id::X25519 => Some("X25519".to_owned()),
+        Id::X448 => Some("X448".to_owned()),
+        Id::EC => {
+            let nid = key.ec_key().ok()?.group().curve_name()?;
+            nid.short_name().ok().map(str::to_owned)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(ossl300))]
+fn negotiated_group(_ssl: &SslRef) -> Option<String> {
+    None
+}
+
 /// Simple implementation of a TCP Stream
 ///
 /// Applying only the most simple methods on this struct it is able to act as
@@ -32,29 +172,36 @@ use super::{
 pub struct DoIpSslStream {
     io: Framed<SslStream<TokioTcpStream>, DoipCodec>,
     config: SocketConfig,
+    /// Boxed as it is rarely set, to keep the stream small
+    capture: Option<Box<StreamCapture>>,
+    trace: FrameTrace,
 }
 
 impl DoIpSslStream {
     /// Creates a new TCP Stream from a Tokio TCP Stream
     pub fn new(io: SslStream<TokioTcpStream>) -> Self {
         DoIpSslStream {
+            trace: FrameTrace::tcp(io.get_ref().peer_addr().ok(), true),
             io: Framed::new(io, DoipCodec {}),
             config: SocketConfig {
                 protocol_version: ProtocolVersion::Iso13400_2012,
+                max_data_size: None,
             },
+            capture: None,
         }
     }
 
     /// Creates a new TCP Stream given a remote address with the default ciphers
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<DoIpSslStream> {
-        let default_ciphers = vec![
-            "ECDHE-RSA-AES128-GCM-SHA256",
-            "ECDHE-RSA-AES256-GCM-SHA384",
-            "ECDHE-ECDSA-AES128-GCM-SHA256",
-            "ECDHE-ECDSA-AES256-GCM-SHA384",
-        ];
+        Self::connect_with_ciphers(addr, DEFAULT_CLIENT_CIPHERS, None).await
+    }
+
+    /// Performs the client side TLS handshake with the default ciphers on an
+    /// established TCP connection
+    pub(crate) async fn client_handshake(stream: TokioTcpStream) -> io::Result<DoIpSslStream> {
+        let ctx = client_context_builder(DEFAULT_CLIENT_CIPHERS, None, None)?.build();
 
-        Self::connect_with_ciphers(addr, &default_ciphers, None).await
+        Self::handshake(stream, Ssl::new(&ctx)?).await
     }
 
     /// Creates a new TCP Stream given a remote address and a list of ciphers
@@ -64,91 +211,135 @@ impl DoIpSslStream {
         tls_ciphers: &[&str],
         eliptic_curve_groups: Option<&[&str]>,
     ) -> io::Result<DoIpSslStream> {
-        match TokioTcpStream::connect(addr).await {
-            Ok(stream) => {
-                // allow unsafe ciphers in order to get better debugging
-                let mut builder = SslContextBuilder::new(SslMethod::tls_client())?;
-
-                builder.set_cipher_list(&tls_ciphers.join(":"))?;
-                builder.set_verify(SslVerifyMode::NONE);
-                // necessary for NULL encryption
-                builder.set_security_level(0);
-                builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
-                builder.set_max_proto_version(Some(SslVersion::TLS1_3))?;
-
-                if let Some(groups) = eliptic_curve_groups {
-                    builder.set_groups_list(&groups.join(":"))?;
-                }
+        let ctx = client_context_builder(tls_ciphers, eliptic_curve_groups, None)?.build();
+
+        Self::connect_with_context(addr, &ctx).await
+    }
+
+    /// Creates a new TCP Stream given a remote address and a list of ciphers,
+    /// writing the TLS session keys to the key log
+    pub async fn connect_with_key_log<A: ToSocketAddrs>(
+        addr: A,
+        tls_ciphers: &[&str],
+        eliptic_curve_groups: Option<&[&str]>,
+        key_log: KeyLog,
+    ) -> io::Result<DoIpSslStream> {
+        let ctx = client_context_builder(tls_ciphers, eliptic_curve_groups, Some(key_log))?.build();
+
+        Self::connect_with_context(addr, &ctx).await
+    }
 
-                let preset_options = builder.options();
-                // this is the flag legacy_renegotiation in openssl client
-                builder.set_options(
-                    preset_options.union(SslOptions::ALLOW_UNSAFE_LEGACY_RENEGOTIATION),
-                );
+    async fn connect_with_context<A: ToSocketAddrs>(
+        addr: A,
+        ctx: &SslContext,
+    ) -> io::Result<DoIpSslStream> {
+        let stream = TokioTcpStream::connect(addr).await?;
 
-                let ctx = builder.build();
-                let ssl = Ssl::new(&ctx)?;
+        Self::handshake(stream, Ssl::new(ctx)?).await
+    }
 
-                let mut stream = SslStream::new(ssl, stream)?;
+    async fn handshake(stream: TokioTcpStream, ssl: Ssl) -> io::Result<DoIpSslStream> {
+        let mut stream = SslStream::new(ssl, stream)?;
 
-                // wait for the actual connection .
-                Pin::new(&mut stream)
-                    .connect()
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // wait for the actual connection .
+        Pin::new(&mut stream)
+            .connect()
+            .await
+            .map_err(io::Error::other)?;
 
-                Ok(Self::apply_codec(stream))
-            }
-            Err(err) => Err(err),
-        }
+        Ok(Self::apply_codec(stream))
     }
 
     fn apply_codec(stream: SslStream<TokioTcpStream>) -> DoIpSslStream {
         DoIpSslStream {
+            trace: FrameTrace::tcp(stream.get_ref().peer_addr().ok(), true),
             io: Framed::new(stream, DoipCodec {}),
             config: SocketConfig {
                 protocol_version: ProtocolVersion::Iso13400_2012,
+                max_data_size: None,
             },
+            capture: None,
         }
     }
 
     /// Send a DoIP frame to the sink
     pub async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        self.config.check_size(&payload)?;
+
         let msg = DoipMessageBuilder::new()
             .protocol_version(self.config.protocol_version)
             .payload(payload)
             .build();
+        let sent = (self.trace.enabled() || self.capture.is_some()).then(|| msg.clone());
 
         match self.io.send(msg).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(SocketSendError::EncodeError(err)),
+            Ok(_) => {
+                // Only frames which were written are recorded
+                if let Some(msg) = sent {
+                    self.trace.sent(&msg);
+                    if let Some(capture) = &self.capture {
+                        capture.sent(&msg);
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.trace.error("Failed to send frame", &err);
+                Err(SocketSendError::EncodeError(err))
+            }
         }
     }
 
     /// Read a DoIP frame off the stream
     pub async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
         let res = self.io.next().await;
+        match res {
+            Some(Ok(ref msg)) => self.trace.received(msg),
+            Some(Err(ref err)) => self.trace.error("Failed to read frame", err),
+            None => self.trace.closed(),
+        }
         if let Some(Ok(ref msg)) = res {
+            if let Some(ref capture) = self.capture {
+                capture.received(msg);
+            }
             if let DoipPayload::DiagnosticMessage(ref diag_msg) = msg.payload {
-                if let Err(e) = self
-                    .send(DoipPayload::DiagnosticMessageAck(DiagnosticMessageAck {
-                        source_address: diag_msg.target_address,
-                        target_address: diag_msg.source_address,
-                        ack_code: DiagnosticAckCode::Acknowledged,
-                        previous_message: diag_msg.message.clone(),
-                    }))
-                    .await
-                {
-                    return Some(Err(CodecError::IoError(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Failed to send DiagnosticMessageAck: {}", e),
-                    ))));
+                if let Err(e) = self.send(diagnostic_ack(diag_msg)).await {
+                    self.trace.error("Failed to send DiagnosticMessageAck", &e);
+                    return Some(Err(CodecError::IoError(io::Error::other(format!(
+                        "Failed to send DiagnosticMessageAck: {}",
+                        e
+                    )))));
                 }
             }
         }
         res
     }
 
+    /// Limit the size of outgoing Diagnostic Messages, e.g. to the maximum data
+    /// size reported in the entity status of the DoIP entity
+    pub fn set_max_data_size(&mut self, max: Option<u32>) {
+        self.config.max_data_size = max
+    }
+
+    /// Record the frames sent and received on this stream, or stop recording
+    /// with `None`
+    ///
+    /// Split halves keep recording.
+    pub fn set_capture(&mut self, capture: Option<Capture>) -> io::Result<()> {
+        self.capture = match capture {
+            Some(capture) => {
+                let stream = self.io.get_ref().get_ref();
+                Some(Box::new(StreamCapture::new(
+                    capture,
+                    stream.local_addr()?,
+                    stream.peer_addr()?,
+                )))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
     /// Splits the TCP Stream into a Read Half and Write Half
     pub fn into_split(
         self,
@@ -164,11 +355,27 @@ impl DoIpSslStream {
         let write = FramedWrite::new(w_half, DoipCodec {});
 
         (
-            TcpStreamReadHalf::new(read, Some(self.config)),
-            TcpStreamWriteHalf::new(write, Some(self.config)),
+            TcpStreamReadHalf::new(read, Some(self.config))
+                .traced(self.trace.clone())
+                .captured(self.capture.clone().map(|capture| *capture)),
+            TcpStreamWriteHalf::new(write, Some(self.config))
+                .traced(self.trace)
+                .captured(self.capture.map(|capture| *capture)),
         )
     }
 
+    /// Returns the parameters negotiated during the TLS handshake and the
+    /// certificates presented by the peer, e.g. for audit logging
+    pub fn tls_info(&self) -> TlsInfo {
+        TlsInfo::from_ssl(self.io.get_ref().ssl())
+    }
+
+    /// Returns true if the TLS session was resumed instead of performing a
+    /// full handshake
+    pub fn session_reused(&self) -> bool {
+        self.io.get_ref().ssl().session_reused()
+    }
+
     /// Get a reference to the inner Tokio TCP Stream
     pub fn get_stream_ref(&self) -> &SslStream<TokioTcpStream> {
         self.io.get_ref()
@@ -180,14 +387,215 @@ impl DoIpSslStream {
     }
 }
 
+/// Reusable TLS client context which resumes sessions on reconnect
+///
+/// Every `DoIpSslStream::connect` performs a full handshake, which is slow on
+/// ECUs with limited crypto hardware. The connector keeps one OpenSSL context
+/// and caches the last session of each peer, so subsequent connects to the
+/// same address can resume it. Use `DoIpSslStream::session_reused` to check
+/// whether resumption succeeded.
+pub struct DoIpSslConnector {
+    ctx: SslContext,
+    peer_index: Index<Ssl, SocketAddr>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Vec<u8>>>>,
+}
+
+impl DoIpSslConnector {
+    /// Creates a new connector with the given ciphers in openssl format and an
+    /// optional key log
+    pub fn new(
+        tls_ciphers: &[&str],
+        eliptic_curve_groups: Option<&[&str]>,
+        key_log: Option<KeyLog>,
+    ) -> io::Result<Self> {
+        let mut builder = client_context_builder(tls_ciphers, eliptic_curve_groups, key_log)?;
+
+        let peer_index = Ssl::new_ex_index::<SocketAddr>()?;
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
+
+        // Sessions are delivered once the handshake completes, or later as a
+        // ticket with TLS 1.3, so they are captured through the callback.
+        // They are kept serialised as OpenSSL invalidates the live session
+        // when a connection is dropped without a clean shutdown, which is
+        // common when ECUs reset.
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+        builder.set_new_session_callback({
+            let sessions = sessions.clone();
+            move |ssl, session| {
+                if let (Some(peer), Ok(der)) = (ssl.ex_data(peer_index), session.to_der()) {
+                    sessions.lock().unwrap().insert(*peer, der);
+                }
+            }
+        });
+
+        Ok(DoIpSslConnector {
+            ctx: builder.build(),
+            peer_index,
+            sessions,
+        })
+    }
+
+    /// Creates a new TCP Stream to the given address, resuming the cached
+    /// session of that peer if there is one
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<DoIpSslStream> {
+        self.connect_with_server_name(addr, None).await
+    }
+
+    /// Like `connect`, but sends the server name with the SNI extension so
+    /// entities hosting several certificates can pick the right one
+    pub async fn connect_with_server_name(
+        &self,
+        addr: SocketAddr,
+        server_name: Option<&str>,
+    ) -> io::Result<DoIpSslStream> {
+        let stream = TokioTcpStream::connect(addr).await?;
+
+        let mut ssl = Ssl::new(&self.ctx)?;
+        ssl.set_ex_data(self.peer_index, addr);
+        if let Some(server_name) = server_name {
+            ssl.set_hostname(server_name)?;
+        }
+
+        let session = self.sessions.lock().unwrap().get(&addr).cloned();
+        if let Some(der) = session {
+            let session = SslSession::from_der(&der)?;
+            // SAFETY: the session was created by a connection using the same
+            // context
+            unsafe { ssl.set_session(&session)? };
+        }
+
+        DoIpSslStream::handshake(stream, ssl).await
+    }
+
+    /// Drop the cached session of a peer so the next connect performs a full
+    /// handshake
+    pub fn forget_session(&self, addr: &SocketAddr) {
+        self.sessions.lock().unwrap().remove(addr);
+    }
+
+    /// Drop all cached sessions
+    pub fn clear_sessions(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+impl fmt::Debug for DoIpSslConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DoIpSslConnector").finish_non_exhaustive()
+    }
+}
+
+fn client_context_builder(
+    tls_ciphers: &[&str],
+    eliptic_curve_groups: Option<&[&str]>,
+    key_log: Option<KeyLog>,
+) -> io::Result<SslContextBuilder> {
+    // allow unsafe ciphers in order to get better debugging
+    let mut builder = SslContextBuilder::new(SslMethod::tls_client())?;
+
+    builder.set_cipher_list(&tls_ciphers.join(":"))?;
+    builder.set_verify(SslVerifyMode::NONE);
+    // necessary for NULL encryption
+    builder.set_security_level(0);
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_max_proto_version(Some(SslVersion::TLS1_3))?;
+
+    if let Some(groups) = eliptic_curve_groups {
+        builder.set_groups_list(&groups.join(":"))?;
+    }
+
+    let preset_options = builder.options();
+    // this is the flag legacy_renegotiation in openssl client
+    builder.set_options(preset_options.union(SslOptions::ALLOW_UNSAFE_LEGACY_RENEGOTIATION));
+
+    if let Some(key_log) = key_log {
+        builder.set_keylog_callback(move |_, line| key_log.log(line));
+    }
+
+    Ok(builder)
+}
+
+impl DoipStream for DoIpSslStream {
+    async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        DoIpSslStream::send(self, payload).await
+    }
+
+    async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        DoIpSslStream::read(self).await
+    }
+}
+
 #[cfg(test)]
 mod test_tcp_stream {
+    use std::{
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
     use doip_definitions::payload::{
         ActivationCode, ActivationType, DoipPayload, RoutingActivationRequest,
         RoutingActivationResponse,
     };
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        ssl::{NameType, Ssl, SslContext, SslMethod, SslVersion},
+        x509::{X509NameBuilder, X509},
+    };
+    use tokio::net::TcpStream as TokioTcpStream;
+    use tokio_openssl::SslStream;
+
+    use crate::tcp::{
+        ssl_stream::{DoIpSslConnector, DoIpSslStream, KeyLog},
+        tcp_stream::TcpStream,
+    };
+
+    const DEFAULT_CIPHERS: &[&str] = &["ECDHE-RSA-AES128-GCM-SHA256"];
+
+    fn server_context() -> SslContext {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "doip-entity").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let mut builder = SslContext::builder(SslMethod::tls_server()).unwrap();
+        builder.set_private_key(&key).unwrap();
+        builder.set_certificate(&cert.build()).unwrap();
+        // TLS 1.2 hands out the session during the handshake, without the
+        // client having to read a ticket afterwards. The id context is
+        // required for the server to accept resumed sessions.
+        builder
+            .set_max_proto_version(Some(SslVersion::TLS1_2))
+            .unwrap();
+        builder.set_session_id_context(b"doip").unwrap();
+        builder.build()
+    }
+
+    async fn accept_tls(
+        listener: &tokio::net::TcpListener,
+        ctx: &SslContext,
+    ) -> SslStream<TokioTcpStream> {
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let mut stream = SslStream::new(Ssl::new(ctx).unwrap(), socket).unwrap();
+        Pin::new(&mut stream).accept().await.unwrap();
 
-    use crate::tcp::{ssl_stream::DoIpSslStream, tcp_stream::TcpStream};
+        stream
+    }
 
     #[ignore]
     #[tokio::test]
@@ -295,4 +703,83 @@ mod test_tcp_stream {
 
         assert_eq!(echo.payload, routing_activation_res)
     }
+
+    #[tokio::test]
+    async fn test_key_log() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = server_context();
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let key_log = {
+            let lines = lines.clone();
+            KeyLog::from_callback(move |line| lines.lock().unwrap().push(line.to_owned()))
+        };
+
+        let (client, _server) = tokio::join!(
+            DoIpSslStream::connect_with_key_log(addr, DEFAULT_CIPHERS, None, key_log),
+            accept_tls(&listener, &ctx)
+        );
+        assert!(client.is_ok());
+
+        let lines = lines.lock().unwrap();
+        assert!(!lines.is_empty());
+        assert!(lines.iter().all(|line| line.split(' ').count() == 3));
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = server_context();
+
+        let connector = DoIpSslConnector::new(DEFAULT_CIPHERS, None, None).unwrap();
+
+        let (first, _server) = tokio::join!(connector.connect(addr), accept_tls(&listener, &ctx));
+        assert!(!first.unwrap().session_reused());
+
+        let (second, _server) = tokio::join!(connector.connect(addr), accept_tls(&listener, &ctx));
+        assert!(second.unwrap().session_reused());
+
+        connector.forget_session(&addr);
+
+        let (third, _server) = tokio::join!(connector.connect(addr), accept_tls(&listener, &ctx));
+        assert!(!third.unwrap().session_reused());
+    }
+
+    #[tokio::test]
+    async fn test_tls_info() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ctx = server_context();
+
+        let connector =
+            DoIpSslConnector::new(DEFAULT_CIPHERS, Some(&["prime256v1"]), None).unwrap();
+
+        let (client, server) = tokio::join!(
+            connector.connect_with_server_name(addr, Some("doip-entity")),
+            accept_tls(&listener, &ctx)
+        );
+        let info = client.unwrap().tls_info();
+
+        assert_eq!(info.version, "TLSv1.2");
+        assert_eq!(info.cipher.as_deref(), Some(DEFAULT_CIPHERS[0]));
+        #[cfg(ossl300)]
+        assert_eq!(info.group.as_deref(), Some("prime256v1"));
+        assert_eq!(
+            info.peer_certificates,
+            vec![server.ssl().certificate().unwrap().to_der().unwrap()]
+        );
+        assert_eq!(info.server_name.as_deref(), Some("doip-entity"));
+        assert_eq!(
+            server.ssl().servername(NameType::HOST_NAME),
+            Some("doip-entity")
+        );
+    }
 }
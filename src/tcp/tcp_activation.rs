@@ -0,0 +1,230 @@
+use std::{io, time::Duration};
+
+use doip_definitions::payload::{
+    ActivationCode, ActivationType, AliveCheckResponse, DoipPayload, RoutingActivationRequest,
+    RoutingActivationResponse,
+};
+use tokio::time::Instant;
+
+use crate::error::ActivationError;
+
+use super::DoipStream;
+
+/// Parameters of a Routing Activation performed by a tester
+///
+/// OEM specific data is not supported: the codec encodes neither the optional
+/// OEM specific field of the request nor that of the response, so activations
+/// which require an authentication exchange through it cannot be performed.
+/// The reserved field of the request is sent in the `buffer` of the Routing
+/// Activation Request and should stay zero as required by ISO 13400-2.
+#[derive(Debug, Clone)]
+pub struct RoutingActivation {
+    /// Logical address of the tester
+    pub tester_address: [u8; 2],
+    /// Activation Type of the request
+    pub activation_type: ActivationType,
+    /// Reserved field of the request, zero as required by ISO 13400-2 unless
+    /// the entity expects vendor specific data in it
+    pub reserved: [u8; 4],
+    /// Time to wait for each Routing Activation Response
+    pub timeout: Duration,
+    /// Time to wait for the activation to be confirmed after the entity
+    /// answered with `ActivatedConfirmationRequired`
+    pub confirmation_timeout: Duration,
+    /// Delay between repeated requests while confirmation is pending
+    pub confirmation_interval: Duration,
+}
+
+impl RoutingActivation {
+    /// Creates a new Routing Activation for the given tester address with the
+    /// default activation type and a zero reserved field
+    pub fn new(tester_address: [u8; 2]) -> Self {
+        RoutingActivation {
+            tester_address,
+            activation_type: ActivationType::Default,
+            reserved: [0, 0, 0, 0],
+            timeout: Duration::from_secs(2),
+            confirmation_timeout: Duration::from_secs(30),
+            confirmation_interval: Duration::from_millis(500),
+        }
+    }
+
+    /// Activates routing on the stream
+    ///
+    /// If the entity requires confirmation, the request is repeated every
+    /// `confirmation_interval` until it is either confirmed, rejected or the
+    /// `confirmation_timeout` elapses.
+    pub async fn activate<S>(
+        &self,
+        stream: &mut S,
+    ) -> Result<RoutingActivationResponse, ActivationError>
+    where
+        S: DoipStream,
+    {
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            let res = self.request(stream).await?;
+
+            match res.activation_code {
+                ActivationCode::SuccessfullyActivated => return Ok(res),
+                ActivationCode::ActivatedConfirmationRequired => {
+                    let deadline =
+                        *deadline.get_or_insert_with(|| Instant::now() + self.confirmation_timeout);
+
+                    if Instant::now() + self.confirmation_interval > deadline {
+                        return Err(ActivationError::ConfirmationTimeout);
+                    }
+
+                    tokio::time::sleep(self.confirmation_interval).await;
+                }
+                code => return Err(ActivationError::Denied(code)),
+            }
+        }
+    }
+
+    async fn request<S>(&self, stream: &mut S) -> Result<RoutingActivationResponse, ActivationError>
+    where
+        S: DoipStream,
+    {
+        stream
+            .send(DoipPayload::RoutingActivationRequest(
+                RoutingActivationRequest {
+                    source_address: self.tester_address,
+                    activation_type: self.activation_type,
+                    buffer: self.reserved,
+                },
+            ))
+            .await?;
+
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                match stream.read().await {
+                    Some(Ok(msg)) => match msg.payload {
+                        DoipPayload::RoutingActivationResponse(res) => return Ok(res),
+                        // The entity may check whether we are still alive while
+                        // it decides on the activation
+                        DoipPayload::AliveCheckRequest(_) => {
+                            stream
+                                .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                                    source_address: self.tester_address,
+                                }))
+                                .await?;
+                        }
+                        _ => {}
+                    },
+                    Some(Err(err)) => return Err(ActivationError::from(err)),
+                    None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+        })
+        .await
+        .map_err(|_| ActivationError::Timeout)?
+    }
+}
+
+#[cfg(test)]
+mod test_tcp_activation {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use doip_definitions::{
+        message::DoipMessage,
+        payload::{ActivationCode, RoutingActivationRequest},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        error::ActivationError,
+        tcp::{
+            tcp_activation::RoutingActivation,
+            tcp_listener::TcpListener,
+            tcp_server::{ActivationDecision, TcpServer},
+            tcp_stream::TcpStream,
+        },
+    };
+
+    const TESTER: [u8; 2] = [0x0e, 0x80];
+
+    async fn start_server<P>(policy: P) -> (SocketAddr, CancellationToken)
+    where
+        P: Fn(SocketAddr, RoutingActivationRequest) -> ActivationDecision + Send + Sync + 'static,
+    {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener)).activation_policy(Arc::new(
+            move |peer, req| std::future::ready(policy(peer, req)),
+        ));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(|_: SocketAddr, _: DoipMessage| async { Vec::new() });
+        tokio::spawn(server.run(handler, shutdown.clone()));
+
+        (addr, shutdown)
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_required() {
+        let requests = AtomicU32::new(0);
+        let (addr, shutdown) = start_server(move |_, _| {
+            if requests.fetch_add(1, Ordering::SeqCst) < 2 {
+                ActivationDecision::confirmation_required()
+            } else {
+                ActivationDecision::accept().reserved([0xca, 0xfe, 0x00, 0x01])
+            }
+        })
+        .await;
+
+        let mut activation = RoutingActivation::new(TESTER);
+        activation.confirmation_interval = Duration::from_millis(10);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let res = activation.activate(&mut client).await.unwrap();
+
+        assert_eq!(res.activation_code, ActivationCode::SuccessfullyActivated);
+        assert_eq!(res.buffer, [0xca, 0xfe, 0x00, 0x01]);
+
+        // A confirmation which never arrives eventually times out
+        let (addr, shutdown2) =
+            start_server(|_, _| ActivationDecision::confirmation_required()).await;
+
+        activation.confirmation_timeout = Duration::from_millis(50);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let res = activation.activate(&mut client).await;
+
+        assert!(matches!(res, Err(ActivationError::ConfirmationTimeout)));
+
+        shutdown.cancel();
+        shutdown2.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_missing_authentication() {
+        let (addr, shutdown) = start_server(|_, _| {
+            ActivationDecision::deny(ActivationCode::DeniedMissingAuthentication)
+        })
+        .await;
+
+        let activation = RoutingActivation::new(TESTER);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let res = activation.activate(&mut client).await;
+        assert!(matches!(
+            res,
+            Err(ActivationError::Denied(
+                ActivationCode::DeniedMissingAuthentication
+            ))
+        ));
+
+        shutdown.cancel();
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use doip_codec::Error as CodecError;
+use doip_definitions::{
+    message::DoipMessage,
+    payload::{ActionCode, DoipPayload, VehicleAnnouncementMessage},
+};
+use tokio::net::TcpStream as TokioTcpStream;
+
+use crate::error::SocketSendError;
+
+#[cfg(feature = "ssl")]
+use super::DoIpSslStream;
+#[cfg(feature = "rustls")]
+use super::DoIpTlsStream;
+use super::{DoipStream, TcpStream};
+
+/// TCP port for unencrypted DoIP connections
+pub const DOIP_TCP_PORT: u16 = 13400;
+
+/// TCP port for TLS secured DoIP connections
+pub const DOIP_TLS_PORT: u16 = 3496;
+
+/// Time to wait for the TLS port to accept a connection
+const TLS_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TLS_SUPPORTED: bool = cfg!(any(feature = "ssl", feature = "rustls"));
+
+/// Decides whether a connection is made with TLS, plain TCP or either
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsPolicy {
+    /// Only connect with TLS, fail if that is not possible
+    TlsOnly,
+    /// Try TLS first and fall back to plain TCP if the TLS port refuses the
+    /// connection or is unreachable
+    ///
+    /// A failed TLS handshake is returned as an error and never downgraded.
+    PreferTls,
+    /// Only connect with plain TCP
+    PlainOnly,
+}
+
+impl TlsPolicy {
+    /// Guesses the policy from a Vehicle Announcement Message
+    ///
+    /// The announcement does not state whether an entity supports TLS, it
+    /// only reports that central security has to be initiated, which does not
+    /// imply TLS. So all entities prefer TLS but accept plain TCP. Pass an
+    /// explicit policy where the vehicle's configuration is known.
+    pub fn guess_from_announcement(announcement: &VehicleAnnouncementMessage) -> Self {
+        match announcement.further_action {
+            ActionCode::RoutingActivationRequired => TlsPolicy::PreferTls,
+            _ => TlsPolicy::PreferTls,
+        }
+    }
+}
+
+/// A DoIP TCP connection which is either plain or TLS secured
+///
+/// Returned by `DoipConnection::connect` so that tools do not have to decide
+/// upfront which stream type to use.
+#[derive(Debug)]
+pub enum DoipConnection {
+    /// Unencrypted connection
+    Plain(TcpStream),
+    /// TLS connection using OpenSSL
+    #[cfg(feature = "ssl")]
+    Ssl(DoIpSslStream),
+    /// TLS connection using rustls
+    #[cfg(feature = "rustls")]
+    Rustls(Box<DoIpTlsStream>),
+}
+
+impl DoipConnection {
+    /// Connects to a DoIP entity on the default ports according to the policy
+    ///
+    /// TLS connections do not validate the certificate of the entity, use
+    /// `DoIpTlsStream::connect` where the trust anchors are known.
+    pub async fn connect(ip: IpAddr, policy: TlsPolicy) -> io::Result<DoipConnection> {
+        Self::connect_to(
+            SocketAddr::new(ip, DOIP_TLS_PORT),
+            SocketAddr::new(ip, DOIP_TCP_PORT),
+            policy,
+        )
+        .await
+    }
+
+    /// Connects to a DoIP entity according to the policy, trying the TLS
+    /// address before the plain address
+    pub async fn connect_to(
+        tls_addr: SocketAddr,
+        plain_addr: SocketAddr,
+        policy: TlsPolicy,
+    ) -> io::Result<DoipConnection> {
+        match policy {
+            TlsPolicy::TlsOnly => tls_handshake(connect_tls_port(tls_addr).await?).await,
+            TlsPolicy::PreferTls if !TLS_SUPPORTED => Self::connect_plain(plain_addr).await,
+            TlsPolicy::PreferTls => match connect_tls_port(tls_addr).await {
+                Ok(stream) => tls_handshake(stream).await,
+                Err(err) if is_unreachable(&err) => Self::connect_plain(plain_addr).await,
+                Err(err) => Err(err),
+            },
+            TlsPolicy::PlainOnly => Self::connect_plain(plain_addr).await,
+        }
+    }
+
+    async fn connect_plain(addr: SocketAddr) -> io::Result<DoipConnection> {
+        Ok(DoipConnection::Plain(TcpStream::connect(addr).await?))
+    }
+
+    /// Returns true if the connection is TLS secured
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, DoipConnection::Plain(_))
+    }
+
+    /// Limit the size of outgoing Diagnostic Messages, e.g. to the maximum data
+    /// size reported in the entity status of the DoIP entity
+    pub fn set_max_data_size(&mut self, max: Option<u32>) {
+        match self {
+            DoipConnection::Plain(stream) => stream.set_max_data_size(max),
+            #[cfg(feature = "ssl")]
+            DoipConnection::Ssl(stream) => stream.set_max_data_size(max),
+            #[cfg(feature = "rustls")]
+            DoipConnection::Rustls(stream) => stream.set_max_data_size(max),
+        }
+    }
+
+    /// Send a DoIP frame to the sink
+    pub async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        match self {
+            DoipConnection::Plain(stream) => stream.send(payload).await,
+            #[cfg(feature = "ssl")]
+            DoipConnection::Ssl(stream) => stream.send(payload).await,
+            #[cfg(feature = "rustls")]
+            DoipConnection::Rustls(stream) => stream.send(payload).await,
+        }
+    }
+
+    /// Read a DoIP frame off the stream
+    pub async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        match self {
+            DoipConnection::Plain(stream) => stream.read().await,
+            #[cfg(feature = "ssl")]
+            DoipConnection::Ssl(stream) => stream.read().await,
+            #[cfg(feature = "rustls")]
+            DoipConnection::Rustls(stream) => stream.read().await,
+        }
+    }
+}
+
+impl DoipStream for DoipConnection {
+    async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        DoipConnection::send(self, payload).await
+    }
+
+    async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        DoipConnection::read(self).await
+    }
+}
+
+async fn connect_tls_port(addr: SocketAddr) -> io::Result<TokioTcpStream> {
+    match tokio::time::timeout(TLS_CONNECT_TIMEOUT, TokioTcpStream::connect(addr)).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timed out connecting to the TLS port",
+        )),
+    }
+}
+
+/// Returns true if the error shows that the entity does not offer TLS, as
+/// opposed to a TLS connection which failed
+fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(feature = "ssl")]
+async fn tls_handshake(stream: TokioTcpStream) -> io::Result<DoipConnection> {
+    Ok(DoipConnection::Ssl(
+        DoIpSslStream::client_handshake(stream).await?,
+    ))
+}
+
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+async fn tls_handshake(stream: TokioTcpStream) -> io::Result<DoipConnection> {
+    Ok(DoipConnection::Rustls(Box::new(
+        DoIpTlsStream::handshake_without_verification(stream).await?,
+    )))
+}
+
+#[cfg(not(any(feature = "ssl", feature = "rustls")))]
+async fn tls_handshake(_stream: TokioTcpStream) -> io::Result<DoipConnection> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TLS requires the ssl or rustls feature",
+    ))
+}
+
+#[cfg(test)]
+mod test_tcp_connection {
+    use crate::tcp::tcp_connection::{DoipConnection, TlsPolicy};
+
+    #[tokio::test]
+    async fn test_falls_back_to_plain() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let plain = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let plain_addr = plain.local_addr().unwrap();
+
+        // Nothing listens on the TLS port
+        let tls = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let tls_addr = tls.local_addr().unwrap();
+        drop(tls);
+
+        let conn = DoipConnection::connect_to(tls_addr, plain_addr, TlsPolicy::PreferTls)
+            .await
+            .unwrap();
+        assert!(!conn.is_tls());
+
+        let conn = DoipConnection::connect_to(tls_addr, plain_addr, TlsPolicy::PlainOnly)
+            .await
+            .unwrap();
+        assert!(!conn.is_tls());
+
+        let conn = DoipConnection::connect_to(tls_addr, plain_addr, TlsPolicy::TlsOnly).await;
+        assert!(conn.is_err());
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[tokio::test]
+    async fn test_failed_handshake_is_not_downgraded() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let plain = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let plain_addr = plain.local_addr().unwrap();
+
+        // The TLS port accepts connections but closes them before the handshake
+        let tls = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let tls_addr = tls.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = tls.accept().await {
+                drop(stream);
+            }
+        });
+
+        let conn = DoipConnection::connect_to(tls_addr, plain_addr, TlsPolicy::PreferTls).await;
+        assert!(conn.is_err());
+    }
+}
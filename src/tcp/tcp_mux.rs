@@ -0,0 +1,380 @@
+use std::sync::{Arc, Mutex};
+
+use doip_definitions::{
+    message::DoipMessage,
+    payload::{DiagnosticAckCode, DiagnosticMessageAck, DoipPayload},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::JoinHandle,
+};
+
+use crate::error::SocketSendError;
+
+use super::tcp_split::{TcpStreamReadHalf, TcpStreamWriteHalf};
+
+/// Cloneable handle multiplexing a single DoIP TCP connection
+///
+/// A DoIP gateway exposes many ECUs behind one connection. The multiplexer
+/// takes both halves of a split stream and runs a background dispatcher on the
+/// read half, handing each incoming frame to the receivers subscribed to the
+/// frame's source address. This allows several tasks to send to different
+/// target addresses and wait for their responses at the same time.
+///
+/// Incoming Diagnostic Messages are acknowledged by the dispatcher in the same
+/// way as `TcpStream::read`. The dispatcher stops when the connection closes or
+/// fails to decode, after which all receivers return `None`.
+pub struct TcpMultiplexer<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    shared: Arc<MultiplexerShared<T>>,
+}
+
+struct MultiplexerShared<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    write: Arc<AsyncMutex<TcpStreamWriteHalf<T>>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    dispatcher: JoinHandle<()>,
+}
+
+struct Subscriber {
+    route: Route,
+    tx: mpsc::UnboundedSender<DoipMessage>,
+}
+
+enum Route {
+    Sources(Vec<[u8; 2]>),
+    Unaddressed,
+}
+
+impl<T> TcpMultiplexer<T>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Creates a new multiplexer from the halves of a split TCP Stream and
+    /// starts the dispatcher on the current Tokio runtime
+    pub fn new(read: TcpStreamReadHalf<T>, write: TcpStreamWriteHalf<T>) -> Self {
+        let write = Arc::new(AsyncMutex::new(write));
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+
+        let dispatcher = tokio::spawn(dispatch(read, write.clone(), subscribers.clone()));
+
+        TcpMultiplexer {
+            shared: Arc::new(MultiplexerShared {
+                write,
+                subscribers,
+                dispatcher,
+            }),
+        }
+    }
+
+    /// Send a DoIP frame to the sink
+    ///
+    /// Sends from different handles are serialised so frames are never
+    /// interleaved on the wire.
+    pub async fn send(&self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        self.shared.write.lock().await.send(payload).await
+    }
+
+    /// Subscribe to frames sent by a single logical address
+    ///
+    /// Used for physical addressing, where responses arrive with the source
+    /// address set to the target address of the request.
+    pub fn subscribe(&self, source_address: [u8; 2]) -> TcpMultiplexerReceiver {
+        self.add_subscriber(Route::Sources(vec![source_address]))
+    }
+
+    /// Subscribe to frames sent by any of the given logical addresses
+    ///
+    /// Used for functional addressing, where a single request is answered by
+    /// several ECUs. Include the functional address itself to also receive
+    /// the acknowledgements of the gateway.
+    pub fn subscribe_functional(&self, source_addresses: &[[u8; 2]]) -> TcpMultiplexerReceiver {
+        self.add_subscriber(Route::Sources(source_addresses.to_vec()))
+    }
+
+    /// Subscribe to frames which carry no source address, such as Alive Check
+    /// Requests and Generic Nacks
+    pub fn subscribe_unaddressed(&self) -> TcpMultiplexerReceiver {
+        self.add_subscriber(Route::Unaddressed)
+    }
+
+    /// Returns true once the dispatcher has stopped and no further frames will
+    /// be received
+    pub fn is_closed(&self) -> bool {
+        self.shared.dispatcher.is_finished()
+    }
+
+    fn add_subscriber(&self, route: Route) -> TcpMultiplexerReceiver {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        // If the dispatcher already stopped the sender is dropped here and the
+        // receiver will report the closed connection immediately.
+        if !self.is_closed() {
+            let mut subscribers = self.shared.subscribers.lock().unwrap();
+            subscribers.retain(|sub| !sub.tx.is_closed());
+            subscribers.push(Subscriber { route, tx });
+        }
+
+        TcpMultiplexerReceiver { rx }
+    }
+
+    #[cfg(test)]
+    fn subscriber_count(&self) -> usize {
+        self.shared.subscribers.lock().unwrap().len()
+    }
+}
+
+impl<T> Clone for TcpMultiplexer<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    fn clone(&self) -> Self {
+        TcpMultiplexer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for MultiplexerShared<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+/// Receiving end of a `TcpMultiplexer` subscription
+pub struct TcpMultiplexerReceiver {
+    rx: mpsc::UnboundedReceiver<DoipMessage>,
+}
+
+impl TcpMultiplexerReceiver {
+    /// Read the next DoIP frame matching the subscription, returns `None` once
+    /// the connection has closed
+    pub async fn recv(&mut self) -> Option<DoipMessage> {
+        self.rx.recv().await
+    }
+}
+
+async fn dispatch<T>(
+    mut read: TcpStreamReadHalf<T>,
+    write: Arc<AsyncMutex<TcpStreamWriteHalf<T>>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+) where
+    T: AsyncRead + AsyncWrite,
+{
+    while let Some(Ok(msg)) = read.read().await {
+        if let DoipPayload::DiagnosticMessage(ref diag_msg) = msg.payload {
+            let ack = DoipPayload::DiagnosticMessageAck(DiagnosticMessageAck {
+                source_address: diag_msg.target_address,
+                target_address: diag_msg.source_address,
+                ack_code: DiagnosticAckCode::Acknowledged,
+                previous_message: diag_msg.message.clone(),
+            });
+
+            if write.lock().await.send(ack).await.is_err() {
+                break;
+            }
+        }
+
+        let source = source_address(&msg.payload);
+
+        subscribers.lock().unwrap().retain(|sub| {
+            let matches = match (&sub.route, source) {
+                (Route::Sources(addrs), Some(addr)) => addrs.contains(&addr),
+                (Route::Unaddressed, None) => true,
+                _ => false,
+            };
+
+            // Drop subscribers whose receiver has gone away, also those for
+            // addresses which never send
+            !sub.tx.is_closed() && (!matches || sub.tx.send(msg.clone()).is_ok())
+        });
+    }
+
+    // Closing every sender ends all pending receives
+    subscribers.lock().unwrap().clear();
+}
+
+fn source_address(payload: &DoipPayload) -> Option<[u8; 2]> {
+    match payload {
+        DoipPayload::RoutingActivationRequest(msg) => Some(msg.source_address),
+        DoipPayload::RoutingActivationResponse(msg) => Some(msg.source_address),
+        DoipPayload::AliveCheckResponse(msg) => Some(msg.source_address),
+        DoipPayload::DiagnosticMessage(msg) => Some(msg.source_address),
+        DoipPayload::DiagnosticMessageAck(msg) => Some(msg.source_address),
+        DoipPayload::DiagnosticMessageNack(msg) => Some(msg.source_address),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_tcp_mux {
+    use doip_definitions::payload::{
+        AliveCheckRequest, DiagnosticAckCode, DiagnosticMessage, DiagnosticMessageAck, DoipPayload,
+    };
+
+    use crate::tcp::{tcp_mux::TcpMultiplexer, tcp_stream::TcpStream};
+
+    const TESTER: [u8; 2] = [0x0e, 0x80];
+    const ECU_A: [u8; 2] = [0x14, 0x11];
+    const ECU_B: [u8; 2] = [0x14, 0x12];
+    const FUNCTIONAL: [u8; 2] = [0xe4, 0x00];
+
+    fn response(source_address: [u8; 2], message: Vec<u8>) -> DoipPayload {
+        DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address,
+            target_address: TESTER,
+            message,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_source_address() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server = TcpStream::new(socket);
+
+        let (read, write) = client.into_split();
+        let mux = TcpMultiplexer::new(read, write);
+
+        let mut ecu_a = mux.subscribe(ECU_A);
+        let mut ecu_b = mux.clone().subscribe(ECU_B);
+        let mut functional = mux.subscribe_functional(&[FUNCTIONAL, ECU_A, ECU_B]);
+        let mut unaddressed = mux.subscribe_unaddressed();
+
+        let _ = server.send(response(ECU_B, vec![0x50, 0x01])).await;
+        let _ = server.send(response(ECU_A, vec![0x50, 0x03])).await;
+        let _ = server
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+
+        let msg = ecu_a.recv().await.unwrap();
+        assert_eq!(msg.payload, response(ECU_A, vec![0x50, 0x03]));
+
+        let msg = ecu_b.recv().await.unwrap();
+        assert_eq!(msg.payload, response(ECU_B, vec![0x50, 0x01]));
+
+        let msg = functional.recv().await.unwrap();
+        assert_eq!(msg.payload, response(ECU_B, vec![0x50, 0x01]));
+        let msg = functional.recv().await.unwrap();
+        assert_eq!(msg.payload, response(ECU_A, vec![0x50, 0x03]));
+
+        let msg = unaddressed.recv().await.unwrap();
+        assert_eq!(
+            msg.payload,
+            DoipPayload::AliveCheckRequest(AliveCheckRequest {})
+        );
+
+        // Both responses are acknowledged by the dispatcher
+        let ack = server.read().await.unwrap().unwrap();
+        assert_eq!(
+            ack.payload,
+            DoipPayload::DiagnosticMessageAck(DiagnosticMessageAck {
+                source_address: TESTER,
+                target_address: ECU_B,
+                ack_code: DiagnosticAckCode::Acknowledged,
+                previous_message: vec![0x50, 0x01],
+            })
+        );
+
+        drop(server);
+        assert!(ecu_a.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drops_closed_subscribers() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server = TcpStream::new(socket);
+
+        let (read, write) = client.into_split();
+        let mux = TcpMultiplexer::new(read, write);
+
+        let mut ecu_a = mux.subscribe(ECU_A);
+        let ecu_b = mux.subscribe(ECU_B);
+        assert_eq!(mux.subscriber_count(), 2);
+
+        // ECU B stays silent, its subscriber goes on the next dispatch
+        drop(ecu_b);
+        let _ = server.send(response(ECU_A, vec![0x50, 0x03])).await;
+        let _ = ecu_a.recv().await.unwrap();
+        assert_eq!(mux.subscriber_count(), 1);
+
+        // ... or on the next subscribe
+        drop(ecu_a);
+        let _functional = mux.subscribe_functional(&[FUNCTIONAL]);
+        assert_eq!(mux.subscriber_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_send() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server = TcpStream::new(socket);
+
+        let (read, write) = client.into_split();
+        let mux = TcpMultiplexer::new(read, write);
+
+        let tasks: Vec<_> = [ECU_A, ECU_B]
+            .into_iter()
+            .map(|ecu| {
+                let mux = mux.clone();
+                tokio::spawn(async move {
+                    let mut rx = mux.subscribe(ecu);
+                    mux.send(DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                        source_address: TESTER,
+                        target_address: ecu,
+                        message: vec![0x10, 0x03],
+                    }))
+                    .await
+                    .unwrap();
+
+                    // Skip the acknowledgement sent by the server
+                    loop {
+                        let msg = rx.recv().await.unwrap();
+                        if let DoipPayload::DiagnosticMessage(_) = msg.payload {
+                            break msg;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..2 {
+            let req = server.read().await.unwrap().unwrap();
+            let DoipPayload::DiagnosticMessage(req) = req.payload else {
+                panic!("expected diagnostic message");
+            };
+            let _ = server
+                .send(response(req.target_address, vec![0x50, 0x03]))
+                .await;
+        }
+
+        for (task, ecu) in tasks.into_iter().zip([ECU_A, ECU_B]) {
+            let msg = task.await.unwrap();
+            assert_eq!(msg.payload, response(ecu, vec![0x50, 0x03]));
+        }
+    }
+}
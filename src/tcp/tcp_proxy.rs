@@ -0,0 +1,564 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use doip_codec::{DoipCodec, Error as CodecError};
+use doip_definitions::message::DoipMessage;
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+};
+use tokio_util::{bytes::BytesMut, codec::Encoder, sync::CancellationToken};
+
+#[cfg(feature = "ssl")]
+use super::DoIpSslConnector;
+use super::{TcpListener, TcpStream, TcpStreamReadHalf, TcpStreamWriteHalf};
+use crate::Capture;
+
+/// Direction of a frame passing a `TcpProxy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the tester to the DoIP entity
+    ToEntity,
+    /// From the DoIP entity to the tester
+    ToTester,
+}
+
+/// Step taken by a `TcpProxy` for a filtered frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterAction {
+    /// Forward a frame, the original or a rewritten one
+    Send(DoipMessage),
+    /// Forward raw bytes, e.g. a corrupted frame
+    SendRaw(Vec<u8>),
+    /// Wait before the next step, holding back all later frames of the same
+    /// direction
+    Delay(Duration),
+}
+
+impl FilterAction {
+    /// Encode the frame and let `corrupt` modify the bytes before they are
+    /// sent
+    pub fn corrupt<F>(msg: DoipMessage, corrupt: F) -> Result<Self, CodecError>
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        let mut buf = BytesMut::new();
+        let mut codec = DoipCodec {};
+        codec.encode(msg, &mut buf)?;
+
+        let mut bytes = buf.to_vec();
+        corrupt(&mut bytes);
+
+        Ok(FilterAction::SendRaw(bytes))
+    }
+}
+
+/// Intercepts the frames passing a `TcpProxy`
+///
+/// Each frame is passed to the filter together with the tester address and
+/// its direction, the returned actions are carried out in order before the
+/// next frame of the same direction is read. A delay therefore holds back the
+/// whole direction, which keeps the frames in order as on the original
+/// connection. Returning no action drops the frame, sending it twice
+/// duplicates it. Frames can be rewritten before they
+/// are sent, e.g. to turn a Diagnostic Message Ack into a Nack or to change a
+/// target address.
+pub trait ProxyFilter: Send + Sync {
+    /// Filter a single frame
+    fn filter(
+        &self,
+        peer: SocketAddr,
+        direction: Direction,
+        msg: DoipMessage,
+    ) -> BoxFuture<'_, Vec<FilterAction>>;
+}
+
+impl<F, Fut> ProxyFilter for F
+where
+    F: Fn(SocketAddr, Direction, DoipMessage) -> Fut + Send + Sync,
+    Fut: Future<Output = Vec<FilterAction>> + Send + 'static,
+{
+    fn filter(
+        &self,
+        peer: SocketAddr,
+        direction: Direction,
+        msg: DoipMessage,
+    ) -> BoxFuture<'_, Vec<FilterAction>> {
+        Box::pin(self(peer, direction, msg))
+    }
+}
+
+/// Transparent DoIP proxy between testers and a DoIP entity
+///
+/// Accepts tester connections on a `TcpListener` and opens a connection to the
+/// upstream entity for each, over TLS if a connector is set. DoIP frames are
+/// forwarded unchanged in both directions, so Routing Activation and the
+/// Diagnostic Message Acks are handled by the entity itself. When either side
+/// closes its connection the other one is closed as well. A `ProxyFilter` can
+/// be set to inspect or tamper with the frames for fault injection. Frames
+/// which cannot be decoded bypass the filter and are forwarded unchanged, so
+/// the receiver rejects them as it would without the proxy.
+pub struct TcpProxy {
+    listener: TcpListener,
+    state: ProxyState,
+}
+
+impl TcpProxy {
+    /// Creates a new proxy forwarding connections from the listener to the
+    /// upstream address
+    pub fn new(listener: TcpListener, upstream: SocketAddr) -> Self {
+        TcpProxy {
+            listener,
+            state: ProxyState {
+                upstream,
+                #[cfg(feature = "ssl")]
+                tls: None,
+                filter: None,
+                capture: None,
+            },
+        }
+    }
+
+    /// Connect to the upstream entity with TLS, e.g. to terminate TLS for
+    /// testers which only support plain connections
+    #[cfg(feature = "ssl")]
+    pub fn upstream_tls(mut self, connector: DoIpSslConnector) -> Self {
+        self.state.tls = Some(Arc::new(connector));
+        self
+    }
+
+    /// Set the filter applied to the frames in both directions
+    pub fn filter(mut self, filter: Arc<dyn ProxyFilter>) -> Self {
+        self.state.filter = Some(filter);
+        self
+    }
+
+    /// Record the frames of the tester and upstream connections
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.state.capture = Some(capture);
+        self
+    }
+
+    /// Returns the reference for the internal listener
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Forward connections until `shutdown` is cancelled
+    ///
+    /// Open connections are closed on shutdown. Only returns an error if
+    /// accepting a connection fails.
+    pub async fn run(self, shutdown: CancellationToken) -> io::Result<()> {
+        let state = Arc::new(self.state);
+        let mut tasks = JoinSet::new();
+
+        let result = loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break Ok(()),
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                res = self.listener.accept() => {
+                    let (tester, peer) = match res {
+                        Ok(conn) => conn,
+                        Err(err) => break Err(err),
+                    };
+
+                    let state = state.clone();
+                    tasks.spawn(async move {
+                        // A failed upstream connect closes the tester connection
+                        let _ = state.relay(tester, peer).await;
+                    });
+                }
+            }
+        };
+
+        tasks.shutdown().await;
+
+        result
+    }
+}
+
+struct ProxyState {
+    upstream: SocketAddr,
+    #[cfg(feature = "ssl")]
+    tls: Option<Arc<DoIpSslConnector>>,
+    filter: Option<Arc<dyn ProxyFilter>>,
+    capture: Option<Capture>,
+}
+
+impl ProxyState {
+    /// Connects to the upstream entity and forwards frames until either side
+    /// closes
+    async fn relay(&self, mut tester: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        tester.set_capture(self.capture.clone())?;
+
+        #[cfg(feature = "ssl")]
+        if let Some(ref connector) = self.tls {
+            let mut entity = connector.connect(self.upstream).await?;
+            entity.set_capture(self.capture.clone())?;
+            self.forward_both(peer, tester.into_split(), entity.into_split())
+                .await;
+            return Ok(());
+        }
+
+        let mut entity = TcpStream::connect(self.upstream).await?;
+        entity.set_capture(self.capture.clone())?;
+        self.forward_both(peer, tester.into_split(), entity.into_split())
+            .await;
+
+        Ok(())
+    }
+
+    async fn forward_both<A, B>(
+        &self,
+        peer: SocketAddr,
+        (tester_read, tester_write): (TcpStreamReadHalf<A>, TcpStreamWriteHalf<A>),
+        (entity_read, entity_write): (TcpStreamReadHalf<B>, TcpStreamWriteHalf<B>),
+    ) where
+        A: AsyncRead + AsyncWrite,
+        B: AsyncRead + AsyncWrite,
+    {
+        tokio::select! {
+            _ = self.forward(peer, Direction::ToEntity, tester_read, entity_write) => {}
+            _ = self.forward(peer, Direction::ToTester, entity_read, tester_write) => {}
+        }
+    }
+
+    /// Forwards frames through the filter, without acknowledging Diagnostic
+    /// Messages
+    async fn forward<R, W>(
+        &self,
+        peer: SocketAddr,
+        direction: Direction,
+        mut from: TcpStreamReadHalf<R>,
+        mut to: TcpStreamWriteHalf<W>,
+    ) where
+        R: AsyncRead + AsyncWrite,
+        W: AsyncRead + AsyncWrite,
+    {
+        loop {
+            let msg = match from.read().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(CodecError::IoError(_))) | None => return,
+                Some(Err(_)) => {
+                    let forwarded = match from.take_invalid_frame().await {
+                        Ok(bytes) => to.send_raw(&bytes).await.is_ok(),
+                        Err(_) => false,
+                    };
+                    if !forwarded {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            let actions = match self.filter {
+                Some(ref filter) => filter.filter(peer, direction, msg).await,
+                None => vec![FilterAction::Send(msg)],
+            };
+
+            for action in actions {
+                let sent = match action {
+                    FilterAction::Send(msg) => to.send_message(msg).await.is_ok(),
+                    FilterAction::SendRaw(bytes) => to.send_raw(&bytes).await.is_ok(),
+                    FilterAction::Delay(delay) => {
+                        tokio::time::sleep(delay).await;
+                        true
+                    }
+                };
+
+                if !sent {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tcp_proxy {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use doip_codec::DoipCodec;
+    use doip_definitions::{
+        message::DoipMessage,
+        payload::{
+            ActivationCode, DiagnosticMessage, DiagnosticMessageNack, DiagnosticNackCode,
+            DoipPayload, GenericNack, NackCode,
+        },
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+    use tokio_util::{bytes::BytesMut, codec::Encoder, sync::CancellationToken};
+
+    use crate::{
+        capture::read_packets,
+        tcp::{
+            DiagnosticResponse, Direction, FilterAction, RoutingActivation, TcpListener, TcpProxy,
+            TcpServer, TcpStream,
+        },
+        test_util::SharedBuf,
+        Capture,
+    };
+
+    /// Starts an entity answering every Diagnostic Message with `50 03`,
+    /// returns its address
+    async fn start_entity(shutdown: &CancellationToken) -> SocketAddr {
+        const ENTITY_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(ENTITY_ADDR).await.unwrap();
+        let entity_addr = listener.local_addr().unwrap();
+        let entity = TcpServer::new(TcpListener::new(listener))
+            .logical_address([0x10, 0x00])
+            .diagnostic_handler(Arc::new(|_, _, _| async {
+                vec![DiagnosticResponse::new(vec![0x50, 0x03])]
+            }));
+
+        let handler = Arc::new(|_: SocketAddr, _: DoipMessage| async { Vec::new() });
+        tokio::spawn(entity.run(handler, shutdown.clone()));
+
+        entity_addr
+    }
+
+    fn request() -> DoipPayload {
+        DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address: [0x0e, 0x80],
+            target_address: [0x10, 0x01],
+            message: vec![0x10, 0x03],
+        })
+    }
+
+    fn response() -> DoipPayload {
+        DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address: [0x10, 0x01],
+            target_address: [0x0e, 0x80],
+            message: vec![0x50, 0x03],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_forwards_both_directions() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let shutdown = CancellationToken::new();
+        let entity_shutdown = CancellationToken::new();
+        let entity_addr = start_entity(&entity_shutdown).await;
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = TcpProxy::new(TcpListener::new(listener), entity_addr);
+        tokio::spawn(proxy.run(shutdown.clone()));
+
+        // Routing activation is answered by the entity behind the proxy
+        let mut tester = TcpStream::connect(proxy_addr).await.unwrap();
+        let res = RoutingActivation::new([0x0e, 0x80])
+            .activate(&mut tester)
+            .await
+            .unwrap();
+        assert_eq!(res.activation_code, ActivationCode::SuccessfullyActivated);
+        assert_eq!(res.source_address, [0x10, 0x00]);
+
+        let _ = tester.send(request()).await;
+
+        let ack = tester.read().await.unwrap().unwrap();
+        assert!(matches!(ack.payload, DoipPayload::DiagnosticMessageAck(_)));
+        let res = tester.read().await.unwrap().unwrap();
+        assert_eq!(res.payload, response());
+
+        // The proxy does not acknowledge on its own
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), tester.read())
+                .await
+                .is_err()
+        );
+
+        // Closing the entity side closes the tester side
+        entity_shutdown.cancel();
+        assert!(tester.read().await.is_none());
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_forwards_undecodable_frames() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let shutdown = CancellationToken::new();
+
+        let entity = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let entity_addr = entity.local_addr().unwrap();
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = TcpProxy::new(TcpListener::new(listener), entity_addr);
+        tokio::spawn(proxy.run(shutdown.clone()));
+
+        // A frame of an unknown payload type followed by a valid one
+        let mut bytes = vec![0x02, 0xfd, 0x12, 0x34, 0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb];
+        let msg = doip_definitions::builder::DoipMessageBuilder::new()
+            .payload(request())
+            .build();
+        let mut valid = BytesMut::new();
+        DoipCodec {}.encode(msg, &mut valid).unwrap();
+        bytes.extend_from_slice(&valid);
+
+        let mut tester = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+        let (mut upstream, _) = entity.accept().await.unwrap();
+        tester.write_all(&bytes).await.unwrap();
+
+        let mut received = vec![0; bytes.len()];
+        upstream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, bytes);
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let shutdown = CancellationToken::new();
+        let entity_addr = start_entity(&shutdown).await;
+
+        // Acks turn into Nacks, responses arrive late and twice and the acks of
+        // the tester never reach the entity
+        let filter = |_, direction, mut msg: DoipMessage| async move {
+            match (direction, &msg.payload) {
+                (Direction::ToTester, DoipPayload::DiagnosticMessageAck(ack)) => {
+                    msg.payload = DoipPayload::DiagnosticMessageNack(DiagnosticMessageNack {
+                        source_address: ack.source_address,
+                        target_address: ack.target_address,
+                        nack_code: DiagnosticNackCode::TargetUnreachable,
+                        previous_message: Vec::new(),
+                    });
+                    vec![FilterAction::Send(msg)]
+                }
+                (Direction::ToTester, DoipPayload::DiagnosticMessage(_)) => vec![
+                    FilterAction::Delay(Duration::from_millis(50)),
+                    FilterAction::Send(msg.clone()),
+                    FilterAction::Send(msg),
+                ],
+                (Direction::ToEntity, DoipPayload::DiagnosticMessageAck(_)) => Vec::new(),
+                _ => vec![FilterAction::Send(msg)],
+            }
+        };
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = TcpProxy::new(TcpListener::new(listener), entity_addr).filter(Arc::new(filter));
+        tokio::spawn(proxy.run(shutdown.clone()));
+
+        let mut tester = TcpStream::connect(proxy_addr).await.unwrap();
+        RoutingActivation::new([0x0e, 0x80])
+            .activate(&mut tester)
+            .await
+            .unwrap();
+        let _ = tester.send(request()).await;
+        let start = Instant::now();
+
+        let nack = tester.read().await.unwrap().unwrap();
+        let DoipPayload::DiagnosticMessageNack(nack) = nack.payload else {
+            panic!("expected diagnostic message nack");
+        };
+        assert_eq!(nack.nack_code, DiagnosticNackCode::TargetUnreachable);
+
+        for _ in 0..2 {
+            let res = tester.read().await.unwrap().unwrap();
+            assert_eq!(res.payload, response());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let shutdown = CancellationToken::new();
+        let entity_addr = start_entity(&shutdown).await;
+
+        let buf = SharedBuf::default();
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = TcpProxy::new(TcpListener::new(listener), entity_addr)
+            .capture(Capture::from_writer(buf.clone()).unwrap());
+        tokio::spawn(proxy.run(shutdown.clone()));
+
+        let mut tester = TcpStream::connect(proxy_addr).await.unwrap();
+        RoutingActivation::new([0x0e, 0x80])
+            .activate(&mut tester)
+            .await
+            .unwrap();
+        let _ = tester.send(request()).await;
+        let _ack = tester.read().await.unwrap().unwrap();
+        let _res = tester.read().await.unwrap().unwrap();
+
+        // Request and response are recorded on both connections of the proxy
+        let packets = read_packets(&buf.0.lock().unwrap()[..]).unwrap();
+        let count = |dst: SocketAddr| packets.iter().filter(|p| p.dst == dst).count();
+        assert!(count(proxy_addr) >= 2);
+        assert!(count(entity_addr) >= 2);
+        assert!(packets.iter().any(|p| p.src == entity_addr));
+
+        shutdown.cancel();
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let msg = doip_definitions::builder::DoipMessageBuilder::new()
+            .payload(request())
+            .build();
+
+        let FilterAction::SendRaw(bytes) =
+            FilterAction::corrupt(msg, |bytes| bytes[1] ^= 0xff).unwrap()
+        else {
+            panic!("expected raw bytes");
+        };
+        assert_eq!(bytes[0], bytes[1]);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_rejected_by_entity() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let shutdown = CancellationToken::new();
+        let entity_addr = start_entity(&shutdown).await;
+
+        // Diagnostic Messages reach the entity with a broken inverse protocol
+        // version
+        let filter = |_, direction, msg: DoipMessage| async move {
+            match (direction, &msg.payload) {
+                (Direction::ToEntity, DoipPayload::DiagnosticMessage(_)) => {
+                    vec![FilterAction::corrupt(msg, |bytes| bytes[1] ^= 0xff).unwrap()]
+                }
+                _ => vec![FilterAction::Send(msg)],
+            }
+        };
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = TcpProxy::new(TcpListener::new(listener), entity_addr).filter(Arc::new(filter));
+        tokio::spawn(proxy.run(shutdown.clone()));
+
+        let mut tester = TcpStream::connect(proxy_addr).await.unwrap();
+        RoutingActivation::new([0x0e, 0x80])
+            .activate(&mut tester)
+            .await
+            .unwrap();
+        let _ = tester.send(request()).await;
+
+        let nack = tester.read().await.unwrap().unwrap();
+        assert_eq!(
+            nack.payload,
+            DoipPayload::GenericNack(GenericNack {
+                nack_code: NackCode::IncorrectPatternFormat,
+            })
+        );
+        assert!(tester.read().await.is_none());
+
+        shutdown.cancel();
+    }
+}
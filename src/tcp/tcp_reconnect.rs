@@ -0,0 +1,336 @@
+use std::{future::Future, io, net::SocketAddr, pin::Pin, time::Duration};
+
+use doip_codec::Error as CodecError;
+use doip_definitions::{message::DoipMessage, payload::DoipPayload};
+use tokio::sync::broadcast;
+
+use crate::error::{ReconnectError, SocketSendError};
+
+#[cfg(feature = "ssl")]
+use super::DoIpSslStream;
+use super::{DoipStream, RoutingActivation, TcpStream};
+
+type Connector<S> = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = io::Result<S>> + Send>> + Send>;
+
+/// Configuration for a `ReconnectingStream`
+///
+/// The backoff starts at `initial_backoff` and is multiplied by
+/// `backoff_multiplier` after every failed attempt, capped at `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Routing Activation performed after every connect
+    pub activation: RoutingActivation,
+    /// Delay before the first reconnection attempt
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between reconnection attempts
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after each failed attempt
+    pub backoff_multiplier: u32,
+    /// Number of attempts before giving up, `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectConfig {
+    /// Creates a new config for the given tester address with default backoff
+    /// settings
+    pub fn new(tester_address: [u8; 2]) -> Self {
+        ReconnectConfig {
+            activation: RoutingActivation::new(tester_address),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            backoff_multiplier: 2,
+            max_attempts: None,
+        }
+    }
+}
+
+/// Connection state changes reported by a `ReconnectingStream`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReconnectEvent {
+    /// The connection to the DoIP entity was lost
+    Disconnected,
+    /// A connection attempt failed and the next one is scheduled after `delay`
+    Reconnecting {
+        /// Number of the failed attempt
+        attempt: u32,
+        /// Delay until the next attempt
+        delay: Duration,
+    },
+    /// The connection was re-established and routing was activated
+    Reconnected {
+        /// Number of attempts it took
+        attempts: u32,
+    },
+    /// Reconnection was abandoned
+    GaveUp {
+        /// Number of attempts made
+        attempts: u32,
+    },
+}
+
+/// DoIP TCP client which survives restarts of the DoIP entity
+///
+/// Wraps a `TcpStream` or `DoIpSslStream` and activates routing after every
+/// connect. When the connection is lost it reconnects with exponential backoff,
+/// re-runs the Routing Activation with the same tester address and reports
+/// `ReconnectError::Reconnected` so the application can repeat the request
+/// which was in flight. Progress is published as `ReconnectEvent`s.
+pub struct ReconnectingStream<S>
+where
+    S: DoipStream,
+{
+    stream: S,
+    connector: Connector<S>,
+    config: ReconnectConfig,
+    events: broadcast::Sender<ReconnectEvent>,
+}
+
+impl<S> ReconnectingStream<S>
+where
+    S: DoipStream,
+{
+    /// Establishes a new connection using the given connector and activates
+    /// routing
+    ///
+    /// The connector is called again for every reconnection attempt.
+    pub async fn connect<F, Fut>(
+        mut connector: F,
+        config: ReconnectConfig,
+    ) -> Result<Self, ReconnectError>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+    {
+        let mut connector: Connector<S> = Box::new(move || Box::pin(connector()));
+        let (events, _) = broadcast::channel(16);
+
+        let (stream, _) = establish(&mut connector, &config, &events).await?;
+
+        Ok(ReconnectingStream {
+            stream,
+            connector,
+            config,
+            events,
+        })
+    }
+
+    /// Subscribe to the connection state changes of this stream
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ReconnectEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send a DoIP frame to the sink
+    ///
+    /// Returns `ReconnectError::Reconnected` if the connection was lost and
+    /// re-established, in which case the frame was not sent.
+    pub async fn send(&mut self, payload: DoipPayload) -> Result<(), ReconnectError> {
+        match self.stream.send(payload).await {
+            Ok(()) => Ok(()),
+            Err(SocketSendError::EncodeError(CodecError::IoError(_)))
+            | Err(SocketSendError::IoError(_)) => self.recover().await,
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Read a DoIP frame off the stream
+    ///
+    /// Returns `ReconnectError::Reconnected` if the connection was lost and
+    /// re-established while waiting.
+    pub async fn read(&mut self) -> Result<DoipMessage, ReconnectError> {
+        match self.stream.read().await {
+            Some(Ok(msg)) => Ok(msg),
+            Some(Err(CodecError::IoError(_))) | None => self.recover().await,
+            Some(Err(err)) => Err(err.into()),
+        }
+    }
+
+    /// Establishes a new connection and replaces the current one with it
+    ///
+    /// The current connection stays in place until the new one is up, it is
+    /// kept if reconnecting fails.
+    pub async fn reconnect(&mut self) -> Result<(), ReconnectError> {
+        let (stream, attempts) = establish(&mut self.connector, &self.config, &self.events).await?;
+        self.stream = stream;
+
+        let _ = self.events.send(ReconnectEvent::Reconnected { attempts });
+
+        Ok(())
+    }
+
+    /// Get a reference to the current inner stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the current inner stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    async fn recover<T>(&mut self) -> Result<T, ReconnectError> {
+        let _ = self.events.send(ReconnectEvent::Disconnected);
+        self.reconnect().await?;
+
+        Err(ReconnectError::Reconnected)
+    }
+}
+
+impl ReconnectingStream<TcpStream> {
+    /// Establishes a new plain TCP connection to the given address and
+    /// activates routing
+    pub async fn connect_tcp(
+        addr: SocketAddr,
+        config: ReconnectConfig,
+    ) -> Result<Self, ReconnectError> {
+        Self::connect(move || TcpStream::connect(addr), config).await
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl ReconnectingStream<DoIpSslStream> {
+    /// Establishes a new TLS connection with the default ciphers to the given
+    /// address and activates routing
+    pub async fn connect_ssl(
+        addr: SocketAddr,
+        config: ReconnectConfig,
+    ) -> Result<Self, ReconnectError> {
+        Self::connect(move || DoIpSslStream::connect(addr), config).await
+    }
+}
+
+async fn establish<S>(
+    connector: &mut Connector<S>,
+    config: &ReconnectConfig,
+    events: &broadcast::Sender<ReconnectEvent>,
+) -> Result<(S, u32), ReconnectError>
+where
+    S: DoipStream,
+{
+    let mut delay = config.initial_backoff;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let err = match connector().await {
+            Ok(mut stream) => match config.activation.activate(&mut stream).await {
+                Ok(_) => return Ok((stream, attempt)),
+                Err(err) => err.into(),
+            },
+            Err(err) => err.into(),
+        };
+
+        if config.max_attempts.is_some_and(|max| attempt >= max) {
+            let _ = events.send(ReconnectEvent::GaveUp { attempts: attempt });
+
+            return Err(ReconnectError::Exhausted {
+                attempts: attempt,
+                source: Box::new(err),
+            });
+        }
+
+        let _ = events.send(ReconnectEvent::Reconnecting { attempt, delay });
+        tokio::time::sleep(delay).await;
+
+        delay = delay
+            .saturating_mul(config.backoff_multiplier)
+            .min(config.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod test_tcp_reconnect {
+    use std::time::Duration;
+
+    use doip_definitions::payload::{
+        ActivationCode, DoipPayload, RoutingActivationRequest, RoutingActivationResponse,
+    };
+
+    use crate::{
+        error::ReconnectError,
+        tcp::{
+            tcp_reconnect::{ReconnectConfig, ReconnectEvent, ReconnectingStream},
+            tcp_stream::TcpStream,
+        },
+    };
+
+    const TESTER: [u8; 2] = [0x0e, 0x80];
+    const ENTITY: [u8; 2] = [0x14, 0x11];
+
+    async fn accept_and_activate(listener: &tokio::net::TcpListener) -> TcpStream {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server = TcpStream::new(socket);
+
+        let req = server.read().await.unwrap().unwrap();
+        let DoipPayload::RoutingActivationRequest(RoutingActivationRequest {
+            source_address, ..
+        }) = req.payload
+        else {
+            panic!("expected routing activation request");
+        };
+        assert_eq!(source_address, TESTER);
+
+        let _ = server
+            .send(DoipPayload::RoutingActivationResponse(
+                RoutingActivationResponse {
+                    logical_address: source_address,
+                    source_address: ENTITY,
+                    activation_code: ActivationCode::SuccessfullyActivated,
+                    buffer: [0, 0, 0, 0],
+                },
+            ))
+            .await;
+
+        server
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_disconnect() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut config = ReconnectConfig::new(TESTER);
+        config.initial_backoff = Duration::from_millis(10);
+
+        let (client, server) = tokio::join!(
+            ReconnectingStream::connect_tcp(addr, config),
+            accept_and_activate(&listener)
+        );
+        let mut client = client.unwrap();
+        let mut events = client.subscribe_events();
+
+        // Simulate a reboot of the entity
+        drop(server);
+
+        let (res, _server) = tokio::join!(client.read(), accept_and_activate(&listener));
+
+        assert!(matches!(res, Err(ReconnectError::Reconnected)));
+        assert_eq!(events.recv().await.unwrap(), ReconnectEvent::Disconnected);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ReconnectEvent::Reconnected { attempts: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut config = ReconnectConfig::new(TESTER);
+        config.initial_backoff = Duration::from_millis(1);
+        config.max_attempts = Some(3);
+
+        let res = ReconnectingStream::connect_tcp(addr, config).await;
+
+        assert!(matches!(
+            res,
+            Err(ReconnectError::Exhausted { attempts: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,789 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, Read},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use doip_codec::DoipCodec;
+use doip_definitions::{
+    message::DoipMessage,
+    payload::{AliveCheckResponse, DiagnosticMessage, DoipPayload},
+};
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+use crate::{capture::read_packets, error::ReplayError};
+
+use super::{DiagnosticHandler, DiagnosticResponse, Direction, DoipStream, RoutingActivation};
+
+/// Well known DoIP ports, used to tell the entity from the tester
+const DOIP_PORTS: [u16; 2] = [13400, 3496];
+/// Time to wait for the final response after a response pending, P2*
+const PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Segments further off the expected sequence number belong to a new
+/// connection between the same addresses
+const SEQ_WINDOW: u32 = 1 << 20;
+
+/// DoIP frame of a recorded session
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    /// Time since the first frame of the session
+    pub time: Duration,
+    /// Whether the tester or the entity sent the frame
+    pub direction: Direction,
+    /// The decoded frame
+    pub message: DoipMessage,
+}
+
+/// Diagnostic request of a recorded session with the responses of the ECU
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedExchange {
+    /// Request sent by the tester
+    pub request: DiagnosticMessage,
+    /// Responses of the target ECU, delays are counted from the request or
+    /// the previous response
+    pub responses: Vec<DiagnosticResponse>,
+}
+
+/// DoIP session between a tester and an entity read from a pcapng capture
+///
+/// Captures written by `Capture` as well as captures of real traffic taken
+/// with Wireshark or tcpdump can be read, TLS sessions only if they were
+/// recorded after decryption by `Capture`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    /// Address of the tester
+    pub tester: SocketAddr,
+    /// Address of the DoIP entity
+    pub entity: SocketAddr,
+    /// Frames in the order they were captured
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    /// Reads the sessions of a pcapng file
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Vec<Recording>> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads the sessions of a pcapng capture, one for each TCP connection
+    ///
+    /// Connections reusing the addresses of an earlier one are told apart by
+    /// a jump of the sequence numbers. A Routing Activation Request following
+    /// other frames than Routing Activation also starts a new session.
+    ///
+    /// The entity of a session is the side on a DoIP port, otherwise the
+    /// receiver of the first Routing Activation Request or the first frame.
+    ///
+    /// Frames cut by missing data or which cannot be decoded are skipped,
+    /// decoding picks up again with the next segment starting with a valid
+    /// header.
+    pub fn read<R: Read>(reader: R) -> io::Result<Vec<Recording>> {
+        struct Session {
+            start: Duration,
+            /// Frames with their source and capture time
+            frames: Vec<(SocketAddr, Duration, DoipMessage)>,
+            streams: HashMap<SocketAddr, Stream>,
+        }
+
+        /// One direction of a session
+        struct Stream {
+            next_seq: u32,
+            /// Undecoded data, starting at a frame boundary
+            buf: BytesMut,
+            /// Whether the next segment continues the data in `buf`
+            in_sync: bool,
+        }
+
+        let mut sessions: Vec<((SocketAddr, SocketAddr), Session)> = Vec::new();
+        let mut codec = DoipCodec {};
+
+        for packet in read_packets(reader)? {
+            let Some(seq) = packet.seq else { continue };
+            if packet.payload.is_empty() {
+                continue;
+            }
+
+            let key = (packet.src.min(packet.dst), packet.src.max(packet.dst));
+            // Only the latest session of the addresses is continued, unless
+            // the segment is far off its sequence numbers
+            let continues = |session: &Session| match session.streams.get(&packet.src) {
+                Some(stream) => {
+                    (stream.next_seq.wrapping_sub(seq) as i32).unsigned_abs() <= SEQ_WINDOW
+                }
+                None => true,
+            };
+            let session = match sessions.iter().rposition(|(k, _)| *k == key) {
+                Some(i) if continues(&sessions[i].1) => &mut sessions[i].1,
+                _ => {
+                    sessions.push((
+                        key,
+                        Session {
+                            start: packet.time,
+                            frames: Vec::new(),
+                            streams: HashMap::new(),
+                        },
+                    ));
+                    &mut sessions.last_mut().unwrap().1
+                }
+            };
+
+            // The capture may start in the middle of a frame
+            let stream = session.streams.entry(packet.src).or_insert(Stream {
+                next_seq: seq,
+                buf: BytesMut::new(),
+                in_sync: false,
+            });
+
+            // Skip retransmitted data, the partial frame before missing data
+            // is dropped
+            let overlap = stream.next_seq.wrapping_sub(seq) as i32;
+            let data = match usize::try_from(overlap) {
+                Ok(overlap) if overlap >= packet.payload.len() => continue,
+                Ok(overlap) => &packet.payload[overlap..],
+                Err(_) => {
+                    stream.buf.clear();
+                    stream.in_sync = false;
+                    &packet.payload[..]
+                }
+            };
+            stream.next_seq = seq.wrapping_add(packet.payload.len() as u32);
+
+            if !stream.in_sync {
+                if !starts_with_header(data) {
+                    continue;
+                }
+                stream.in_sync = true;
+            }
+            stream.buf.extend_from_slice(data);
+
+            loop {
+                match codec.decode(&mut stream.buf) {
+                    Ok(Some(msg)) => session.frames.push((packet.src, packet.time, msg)),
+                    Ok(None) => break,
+                    Err(_) => {
+                        stream.buf.clear();
+                        stream.in_sync = false;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Split off a new session at each Routing Activation Request which
+        // follows other frames
+        let is_activation = |msg: &DoipMessage| {
+            matches!(
+                msg.payload,
+                DoipPayload::RoutingActivationRequest(_)
+                    | DoipPayload::RoutingActivationResponse(_)
+            )
+        };
+        let sessions = sessions.into_iter().flat_map(|(key, session)| {
+            let mut split = vec![(key, session.start, Vec::new())];
+            for (src, time, msg) in session.frames {
+                let (_, _, frames) = split.last().unwrap();
+                if matches!(msg.payload, DoipPayload::RoutingActivationRequest(_))
+                    && frames.iter().any(|(_, _, msg)| !is_activation(msg))
+                {
+                    split.push((key, time, Vec::new()));
+                }
+                split.last_mut().unwrap().2.push((src, time, msg));
+            }
+            split
+        });
+
+        let recordings = sessions
+            .filter(|(_, _, frames)| !frames.is_empty())
+            .map(|((a, b), start, frames)| {
+                let entity = if DOIP_PORTS.contains(&a.port()) != DOIP_PORTS.contains(&b.port()) {
+                    if DOIP_PORTS.contains(&a.port()) {
+                        a
+                    } else {
+                        b
+                    }
+                } else {
+                    let activation = frames.iter().find(|(_, _, msg)| {
+                        matches!(msg.payload, DoipPayload::RoutingActivationRequest(_))
+                    });
+                    let (src, _, _) = activation.unwrap_or(&frames[0]);
+                    if *src == a {
+                        b
+                    } else {
+                        a
+                    }
+                };
+
+                Recording {
+                    tester: if entity == a { b } else { a },
+                    entity,
+                    frames: frames
+                        .into_iter()
+                        .map(|(src, time, message)| RecordedFrame {
+                            time: time.saturating_sub(start),
+                            direction: if src == entity {
+                                Direction::ToTester
+                            } else {
+                                Direction::ToEntity
+                            },
+                            message,
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(recordings)
+    }
+
+    /// Pairs the Diagnostic Messages of the tester with the responses of the
+    /// target ECU up to the next request
+    ///
+    /// Only responses sent from the target address of the request are paired,
+    /// so functionally addressed requests have no responses: the ECUs answer
+    /// with their own addresses, which a `DiagnosticResponse` cannot carry.
+    pub fn exchanges(&self) -> Vec<RecordedExchange> {
+        let mut exchanges: Vec<RecordedExchange> = Vec::new();
+        let mut last = Duration::ZERO;
+
+        for frame in &self.frames {
+            let DoipPayload::DiagnosticMessage(ref msg) = frame.message.payload else {
+                continue;
+            };
+
+            match frame.direction {
+                Direction::ToEntity => exchanges.push(RecordedExchange {
+                    request: msg.clone(),
+                    responses: Vec::new(),
+                }),
+                Direction::ToTester => {
+                    let Some(exchange) = exchanges.last_mut() else {
+                        continue;
+                    };
+                    if msg.source_address != exchange.request.target_address {
+                        continue;
+                    }
+
+                    exchange.responses.push(
+                        DiagnosticResponse::new(msg.message.clone())
+                            .after(frame.time.saturating_sub(last)),
+                    );
+                }
+            }
+
+            last = frame.time;
+        }
+
+        exchanges
+    }
+}
+
+/// Target address and UDS data of a request
+type RequestKey = ([u8; 2], Vec<u8>);
+
+/// Answers Diagnostic Messages with the responses of recorded sessions
+///
+/// Requests are matched by target address and UDS data. A request which was
+/// recorded several times is answered with the recorded responses in turn,
+/// starting over after the last one. Requests which were not recorded are only
+/// acknowledged, requests to addresses without any recording are rejected with
+/// `UnknownTargetAddress`.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use doip_sockets::tcp::{Recording, ReplayEcu, TcpListener, TcpServer};
+/// # async fn replay(listener: TcpListener) -> std::io::Result<()> {
+/// let recordings = Recording::open("session.pcapng")?;
+/// let ecu = ReplayEcu::new(&recordings);
+/// let server = TcpServer::new(listener).diagnostic_handler(Arc::new(ecu));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ReplayEcu {
+    responses: HashMap<RequestKey, Vec<Vec<DiagnosticResponse>>>,
+    targets: HashSet<[u8; 2]>,
+    /// Number of times each request was answered
+    answered: Mutex<HashMap<RequestKey, usize>>,
+}
+
+impl ReplayEcu {
+    /// Creates a new simulated ECU from the exchanges of the recordings
+    pub fn new(recordings: &[Recording]) -> Self {
+        let mut responses: HashMap<_, Vec<_>> = HashMap::new();
+
+        for exchange in recordings.iter().flat_map(Recording::exchanges) {
+            let key = (exchange.request.target_address, exchange.request.message);
+            responses.entry(key).or_default().push(exchange.responses);
+        }
+
+        ReplayEcu {
+            targets: responses.keys().map(|(target, _)| *target).collect(),
+            responses,
+            answered: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl DiagnosticHandler for ReplayEcu {
+    fn handle(
+        &self,
+        _source: [u8; 2],
+        target: [u8; 2],
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Vec<DiagnosticResponse>> {
+        let key = (target, message);
+        let responses = match self.responses.get(&key) {
+            Some(recorded) => {
+                let mut answered = self.answered.lock().unwrap();
+                let count = answered.entry(key).or_default();
+                *count += 1;
+                recorded[(*count - 1) % recorded.len()].clone()
+            }
+            None => Vec::new(),
+        };
+
+        Box::pin(async move { responses })
+    }
+
+    fn has_target(&self, target: [u8; 2]) -> bool {
+        self.targets.contains(&target)
+    }
+}
+
+/// Request whose responses differ from the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayMismatch {
+    /// The replayed request
+    pub request: DiagnosticMessage,
+    /// Recorded final responses
+    pub expected: Vec<Vec<u8>>,
+    /// Final responses received during the replay
+    pub actual: Vec<Vec<u8>>,
+}
+
+/// Result of a `ReplayTester` run
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplayReport {
+    /// Number of replayed requests
+    pub exchanges: usize,
+    /// Requests whose responses differ from the recording
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+impl ReplayReport {
+    /// Whether all responses matched the recording
+    pub fn is_match(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Replays the requests of a recorded session against a DoIP entity and
+/// compares the responses
+///
+/// Response pending messages are not compared, as their number depends on the
+/// timing of the ECU, neither are the responses to functionally addressed
+/// requests, see `Recording::exchanges`. Routing is activated with the
+/// recorded Routing Activation Request first, if the recording has one.
+#[derive(Debug, Clone)]
+pub struct ReplayTester {
+    recording: Recording,
+    timeout: Duration,
+}
+
+impl ReplayTester {
+    /// Creates a new replay of the recording
+    pub fn new(recording: Recording) -> Self {
+        ReplayTester {
+            recording,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Time to wait for each response, defaults to 2 seconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Replays the session on a connected stream
+    pub async fn run<S: DoipStream>(&self, stream: &mut S) -> Result<ReplayReport, ReplayError> {
+        let activation =
+            self.recording
+                .frames
+                .iter()
+                .find_map(|frame| match frame.message.payload {
+                    DoipPayload::RoutingActivationRequest(ref req) => Some(req),
+                    _ => None,
+                });
+
+        if let Some(req) = activation {
+            RoutingActivation {
+                activation_type: req.activation_type,
+                reserved: req.buffer,
+                ..RoutingActivation::new(req.source_address)
+            }
+            .activate(stream)
+            .await?;
+        }
+
+        let mut report = ReplayReport::default();
+
+        for exchange in self.recording.exchanges() {
+            let expected: Vec<Vec<u8>> = exchange
+                .responses
+                .into_iter()
+                .map(|res| res.message)
+                .filter(|msg| !is_pending(msg))
+                .collect();
+
+            let actual = self
+                .exchange(stream, &exchange.request, expected.len())
+                .await?;
+
+            report.exchanges += 1;
+            if actual != expected {
+                report.mismatches.push(ReplayMismatch {
+                    request: exchange.request,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Sends a request and collects the final responses until `expected`
+    /// responses arrived, the request was rejected or the timeout elapsed
+    async fn exchange<S: DoipStream>(
+        &self,
+        stream: &mut S,
+        request: &DiagnosticMessage,
+        expected: usize,
+    ) -> Result<Vec<Vec<u8>>, ReplayError> {
+        stream
+            .send(DoipPayload::DiagnosticMessage(request.clone()))
+            .await?;
+
+        let mut responses = Vec::new();
+        let mut deadline = Instant::now() + self.timeout;
+
+        // Wait for the acknowledgement even if no response is expected
+        let mut acknowledged = false;
+
+        while !acknowledged || responses.len() < expected {
+            let Ok(res) = tokio::time::timeout_at(deadline, stream.read()).await else {
+                break;
+            };
+            let msg = res.ok_or(ReplayError::Closed)??;
+
+            match msg.payload {
+                DoipPayload::DiagnosticMessageAck(_) => acknowledged = true,
+                DoipPayload::DiagnosticMessageNack(_) => break,
+                // Responses before the acknowledgement are left over from the
+                // previous request
+                DoipPayload::DiagnosticMessage(res)
+                    if acknowledged && res.source_address == request.target_address =>
+                {
+                    if is_pending(&res.message) {
+                        deadline = Instant::now() + PENDING_TIMEOUT.max(self.timeout);
+                    } else {
+                        responses.push(res.message);
+                        deadline = Instant::now() + self.timeout;
+                    }
+                }
+                DoipPayload::AliveCheckRequest(_) => {
+                    stream
+                        .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                            source_address: request.source_address,
+                        }))
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(responses)
+    }
+}
+
+/// Whether the data starts with a DoIP header the codec accepts
+fn starts_with_header(data: &[u8]) -> bool {
+    data.len() >= 8 && DoipCodec {}.decode(&mut BytesMut::from(data)).is_ok()
+}
+
+/// Negative response with code 0x78, response pending
+fn is_pending(message: &[u8]) -> bool {
+    matches!(message, [0x7f, _, 0x78])
+}
+
+#[cfg(test)]
+mod test_tcp_replay {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use doip_definitions::{
+        builder::DoipMessageBuilder,
+        payload::{
+            ActivationCode, ActivationType, DiagnosticMessage, DoipPayload,
+            RoutingActivationRequest, RoutingActivationResponse,
+        },
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        tcp::{
+            DiagnosticResponse, Direction, Recording, ReplayEcu, ReplayTester, RoutingActivation,
+            TcpListener, TcpServer, TcpStream,
+        },
+        test_util::SharedBuf,
+        Capture,
+    };
+
+    fn request(message: Vec<u8>) -> DoipPayload {
+        DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address: [0x0e, 0x80],
+            target_address: [0x10, 0x01],
+            message,
+        })
+    }
+
+    /// Starts a server answering with `handler` and returns its address
+    async fn start_server(
+        handler: impl crate::tcp::DiagnosticHandler + 'static,
+        shutdown: CancellationToken,
+    ) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            TcpServer::new(TcpListener::new(listener)).diagnostic_handler(Arc::new(handler));
+
+        tokio::spawn(server.run(Arc::new(|_, _| async { Vec::new() }), shutdown));
+        addr
+    }
+
+    /// Removes the `index`th packet from a pcapng capture, e.g. to simulate a
+    /// lost segment
+    fn drop_packet(capture: &[u8], index: usize) -> Vec<u8> {
+        const BLOCK_ENHANCED_PACKET: u32 = 6;
+
+        let mut bytes = Vec::new();
+        let mut rest = capture;
+        let mut packets = 0;
+
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+
+            if block_type == BLOCK_ENHANCED_PACKET {
+                packets += 1;
+            }
+            if block_type != BLOCK_ENHANCED_PACKET || packets != index + 1 {
+                bytes.extend_from_slice(&rest[..len]);
+            }
+            rest = &rest[len..];
+        }
+
+        bytes
+    }
+
+    /// Records a session against an ECU answering a slow routine with a
+    /// response pending first
+    async fn record_session() -> Recording {
+        let shutdown = CancellationToken::new();
+        let ecu = |_, _, message: Vec<u8>| async move {
+            match message[..] {
+                [0x10, 0x03] => vec![DiagnosticResponse::new(vec![0x50, 0x03])],
+                [0x31, 0x01, 0xff, 0x00] => vec![
+                    DiagnosticResponse::pending(0x31),
+                    DiagnosticResponse::new(vec![0x71, 0x01, 0xff, 0x00])
+                        .after(Duration::from_millis(50)),
+                ],
+                _ => vec![],
+            }
+        };
+        let addr = start_server(ecu, shutdown.clone()).await;
+
+        let buf = SharedBuf::default();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .set_capture(Some(Capture::from_writer(buf.clone()).unwrap()))
+            .unwrap();
+
+        RoutingActivation::new([0x0e, 0x80])
+            .activate(&mut client)
+            .await
+            .unwrap();
+
+        for (message, frames) in [(vec![0x10, 0x03], 2), (vec![0x31, 0x01, 0xff, 0x00], 3)] {
+            let _ = client.send(request(message)).await;
+            for _ in 0..frames {
+                let _ = client.read().await.unwrap().unwrap();
+            }
+        }
+        shutdown.cancel();
+
+        let mut recordings = Recording::read(&buf.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(recordings.len(), 1);
+        recordings.remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_read_recording() {
+        let recording = record_session().await;
+
+        assert_eq!(recording.frames[0].direction, Direction::ToEntity);
+        assert!(matches!(
+            recording.frames[0].message.payload,
+            DoipPayload::RoutingActivationRequest(_)
+        ));
+
+        let exchanges = recording.exchanges();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].request.message, [0x10, 0x03]);
+        assert_eq!(exchanges[0].responses[0].message, [0x50, 0x03]);
+
+        let routine = &exchanges[1].responses;
+        assert_eq!(routine.len(), 2);
+        assert_eq!(routine[0].message, [0x7f, 0x31, 0x78]);
+        assert!(routine[0].delay + routine[1].delay >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_read_skips_broken_frames() {
+        let tester: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let entity: SocketAddr = "127.0.0.1:13400".parse().unwrap();
+        let response = |message: Vec<u8>| {
+            DoipMessageBuilder::new()
+                .payload(DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                    source_address: [0x10, 0x01],
+                    target_address: [0x0e, 0x80],
+                    message,
+                }))
+                .build()
+        };
+
+        let buf = SharedBuf::default();
+        let capture = Capture::from_writer(buf.clone()).unwrap();
+        capture.record_tcp(
+            tester,
+            entity,
+            &DoipMessageBuilder::new()
+                .payload(request(vec![0x22, 0xf1, 0x90]))
+                .build(),
+        );
+        capture.record_tcp(entity, tester, &response(vec![0x62, 0xf1, 0x90]));
+        // Recorded in three segments, the second one gets lost
+        capture.record_tcp(entity, tester, &response(vec![0x36; 140_000]));
+        capture.record_tcp(entity, tester, &response(vec![0x76, 0x01]));
+        // Bytes which do not form a DoIP frame
+        capture.record_tcp_bytes(entity, tester, &[0xff; 16]);
+        capture.record_tcp(entity, tester, &response(vec![0x76, 0x02]));
+
+        let bytes = drop_packet(&buf.0.lock().unwrap(), 3);
+        let recordings = Recording::read(&bytes[..]).unwrap();
+        assert_eq!(recordings.len(), 1);
+
+        let responses: Vec<_> = recordings[0]
+            .frames
+            .iter()
+            .filter(|frame| frame.direction == Direction::ToTester)
+            .map(|frame| match frame.message.payload {
+                DoipPayload::DiagnosticMessage(ref msg) => msg.message.clone(),
+                ref payload => panic!("unexpected {:?}", payload),
+            })
+            .collect();
+        assert_eq!(
+            responses,
+            [vec![0x62, 0xf1, 0x90], vec![0x76, 0x01], vec![0x76, 0x02]]
+        );
+    }
+
+    #[test]
+    fn test_read_splits_sessions() {
+        let tester: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let entity: SocketAddr = "127.0.0.1:13400".parse().unwrap();
+        let frame = |payload| DoipMessageBuilder::new().payload(payload).build();
+        let activation_request = frame(DoipPayload::RoutingActivationRequest(
+            RoutingActivationRequest {
+                source_address: [0x0e, 0x80],
+                activation_type: ActivationType::Default,
+                buffer: [0, 0, 0, 0],
+            },
+        ));
+        let activation_response = frame(DoipPayload::RoutingActivationResponse(
+            RoutingActivationResponse {
+                logical_address: [0x0e, 0x80],
+                source_address: [0x10, 0x00],
+                activation_code: ActivationCode::SuccessfullyActivated,
+                buffer: [0, 0, 0, 0],
+            },
+        ));
+        let response = frame(DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address: [0x10, 0x01],
+            target_address: [0x0e, 0x80],
+            message: vec![0x50, 0x03],
+        }));
+
+        let buf = SharedBuf::default();
+        let capture = Capture::from_writer(buf.clone()).unwrap();
+        for _ in 0..2 {
+            capture.record_tcp(tester, entity, &activation_request);
+            capture.record_tcp(entity, tester, &activation_response);
+            capture.record_tcp(tester, entity, &frame(request(vec![0x10, 0x03])));
+            capture.record_tcp(entity, tester, &response);
+        }
+        // The next connection reuses the addresses, its sequence numbers are
+        // far off those of the previous one
+        capture.record_tcp_bytes(tester, entity, &[0; 1_100_000]);
+        capture.record_tcp(tester, entity, &frame(request(vec![0x10, 0x03])));
+        capture.record_tcp(entity, tester, &response);
+
+        let mut bytes = buf.0.lock().unwrap().clone();
+        for _ in 0..17 {
+            bytes = drop_packet(&bytes, 8);
+        }
+        let recordings = Recording::read(&bytes[..]).unwrap();
+
+        let frames: Vec<_> = recordings.iter().map(|rec| rec.frames.len()).collect();
+        assert_eq!(frames, [4, 4, 2]);
+        assert!(recordings.iter().all(|rec| rec.entity == entity));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let recording = record_session().await;
+
+        // The tester replay against the recorded ECU matches
+        let shutdown = CancellationToken::new();
+        let ecu = ReplayEcu::new(std::slice::from_ref(&recording));
+        let addr = start_server(ecu, shutdown.clone()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let report = ReplayTester::new(recording.clone())
+            .run(&mut client)
+            .await
+            .unwrap();
+        assert_eq!(report.exchanges, 2);
+        assert!(report.is_match());
+
+        // An ECU answering differently is reported
+        let changed = |_, _, message: Vec<u8>| async move {
+            match message[..] {
+                [0x10, 0x03] => vec![DiagnosticResponse::new(vec![0x7f, 0x10, 0x22])],
+                _ => vec![DiagnosticResponse::new(vec![0x71, 0x01, 0xff, 0x00])],
+            }
+        };
+        let addr = start_server(changed, shutdown.clone()).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let report = ReplayTester::new(recording).run(&mut client).await.unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].actual, [vec![0x7f, 0x10, 0x22]]);
+
+        shutdown.cancel();
+    }
+}
@@ -0,0 +1,1402 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use doip_codec::Error as CodecError;
+use doip_definitions::{
+    message::DoipMessage,
+    payload::{
+        ActivationCode, AliveCheckRequest, DiagnosticMessage, DiagnosticMessageNack,
+        DiagnosticNackCode, DoipPayload, GenericNack, NackCode, RoutingActivationRequest,
+        RoutingActivationResponse,
+    },
+};
+use futures::future::{join_all, BoxFuture};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{diagnostic_ack, TcpListener, TcpStream};
+use crate::Capture;
+
+/// Handles the DoIP frames received by a `TcpServer`
+///
+/// Each received frame is passed to the handler together with the address of
+/// the peer, the returned payloads are sent back in order before the next frame
+/// is read. A frame and its responses form one exchange which is never cut off
+/// by a graceful shutdown.
+///
+/// Routing Activation Requests and Alive Check Responses are handled by the
+/// server itself and are not passed to the handler.
+pub trait ServerHandler: Send + Sync {
+    /// Handle a single frame and return the responses
+    fn handle(&self, peer: SocketAddr, msg: DoipMessage) -> BoxFuture<'_, Vec<DoipPayload>>;
+}
+
+impl<F, Fut> ServerHandler for F
+where
+    F: Fn(SocketAddr, DoipMessage) -> Fut + Send + Sync,
+    Fut: Future<Output = Vec<DoipPayload>> + Send + 'static,
+{
+    fn handle(&self, peer: SocketAddr, msg: DoipMessage) -> BoxFuture<'_, Vec<DoipPayload>> {
+        Box::pin(self(peer, msg))
+    }
+}
+
+/// Outcome of an `ActivationPolicy` check
+///
+/// Carries the activation code and the 4 byte reserved field sent back in the
+/// Routing Activation Response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivationDecision {
+    /// Activation code of the response
+    pub code: ActivationCode,
+    /// Reserved field of the response, zero unless the tester expects vendor
+    /// specific data in it
+    pub reserved: [u8; 4],
+}
+
+impl ActivationDecision {
+    /// Accept the activation
+    pub fn accept() -> Self {
+        ActivationDecision {
+            code: ActivationCode::SuccessfullyActivated,
+            reserved: [0, 0, 0, 0],
+        }
+    }
+
+    /// Register the socket but require the activation to be confirmed, the
+    /// tester repeats the request until it is accepted or denied
+    pub fn confirmation_required() -> Self {
+        ActivationDecision {
+            code: ActivationCode::ActivatedConfirmationRequired,
+            reserved: [0, 0, 0, 0],
+        }
+    }
+
+    /// Deny the activation with the given code
+    pub fn deny(code: ActivationCode) -> Self {
+        ActivationDecision {
+            code,
+            reserved: [0, 0, 0, 0],
+        }
+    }
+
+    /// Set the reserved field of the response
+    pub fn reserved(mut self, data: [u8; 4]) -> Self {
+        self.reserved = data;
+        self
+    }
+}
+
+/// Decides on Routing Activation Requests received by a `TcpServer`
+///
+/// The policy is consulted before the socket is registered and can implement
+/// OEM specific authentication based on the activation type and the reserved
+/// field of the request. Only accepted activations and activations
+/// requiring confirmation go on to register the socket, which may still deny
+/// them if the source address is in use or all sockets are taken.
+pub trait ActivationPolicy: Send + Sync {
+    /// Check a Routing Activation Request
+    fn check(
+        &self,
+        peer: SocketAddr,
+        req: RoutingActivationRequest,
+    ) -> BoxFuture<'_, ActivationDecision>;
+}
+
+impl<F, Fut> ActivationPolicy for F
+where
+    F: Fn(SocketAddr, RoutingActivationRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = ActivationDecision> + Send + 'static,
+{
+    fn check(
+        &self,
+        peer: SocketAddr,
+        req: RoutingActivationRequest,
+    ) -> BoxFuture<'_, ActivationDecision> {
+        Box::pin(self(peer, req))
+    }
+}
+
+/// UDS response emitted by a `DiagnosticHandler`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticResponse {
+    /// Time to wait before sending, counted from the previous response
+    pub delay: Duration,
+    /// UDS response data
+    pub message: Vec<u8>,
+}
+
+impl DiagnosticResponse {
+    /// A response which is sent right away
+    pub fn new(message: Vec<u8>) -> Self {
+        DiagnosticResponse {
+            delay: Duration::ZERO,
+            message,
+        }
+    }
+
+    /// A negative response with response pending (`0x78`) for the service
+    pub fn pending(sid: u8) -> Self {
+        Self::new(vec![0x7f, sid, 0x78])
+    }
+
+    /// Send the response after the given delay
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Handles the UDS requests of Diagnostic Messages received by a `TcpServer`
+///
+/// The handler is called with the source and target address and the UDS data
+/// of each Diagnostic Message. The returned responses are sent in order, each
+/// after its delay, from the target back to the source. This allows a single
+/// request to be answered with response pending first and the final response
+/// later, as real ECUs do. State such as the active session or a security
+/// access seed can be kept in the handler itself.
+pub trait DiagnosticHandler: Send + Sync {
+    /// Handle a UDS request and return the responses
+    fn handle(
+        &self,
+        source: [u8; 2],
+        target: [u8; 2],
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Vec<DiagnosticResponse>>;
+
+    /// Whether the target address is served by this handler, Diagnostic
+    /// Messages to other targets are answered with a Diagnostic Message Nack
+    fn has_target(&self, _target: [u8; 2]) -> bool {
+        true
+    }
+}
+
+impl<F, Fut> DiagnosticHandler for F
+where
+    F: Fn([u8; 2], [u8; 2], Vec<u8>) -> Fut + Send + Sync,
+    Fut: Future<Output = Vec<DiagnosticResponse>> + Send + 'static,
+{
+    fn handle(
+        &self,
+        source: [u8; 2],
+        target: [u8; 2],
+        message: Vec<u8>,
+    ) -> BoxFuture<'_, Vec<DiagnosticResponse>> {
+        Box::pin(self(source, target, message))
+    }
+}
+
+/// Outcome of a `TcpServer` shutdown
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShutdownReport {
+    /// Connections which finished their exchanges and were closed cleanly
+    pub closed: Vec<SocketAddr>,
+    /// Connections which were still busy when the drain timeout elapsed
+    pub force_closed: Vec<SocketAddr>,
+}
+
+/// DoIP TCP server run loop with graceful shutdown
+///
+/// Accepts connections from a `TcpListener` and serves each on its own task
+/// until the cancellation token is triggered. On shutdown no new connections
+/// are accepted and no further frames are read, while exchanges already in
+/// progress are given until the drain timeout to complete. Exchanges still
+/// running after that are interrupted between frames, so that a peer never
+/// receives a partial frame. Connections are then closed and reported in a
+/// `ShutdownReport`.
+///
+/// The server answers Routing Activation Requests and limits the number of
+/// activated sockets. When all sockets are in use, registered connections are
+/// alive checked and those which do not respond in time are closed to make
+/// room, as described in ISO 13400-2. The same check is applied when a source
+/// address is already registered on a different socket, which is denied while
+/// the other socket is alive. Requests can be checked by an `ActivationPolicy`
+/// first, without one every activation is accepted.
+///
+/// Diagnostic Messages are only accepted from the source address activated on
+/// the socket, otherwise they are rejected with `InvalidSourceAddress` and the
+/// socket is closed. Accepted messages are acknowledged and passed to the
+/// `DiagnosticHandler` if one is set and to the `ServerHandler` otherwise.
+///
+/// Headers are checked as described in ISO 13400-2 and rejected with a Generic
+/// Nack. Frames of an unknown payload type or longer than the maximum payload
+/// length are discarded, while an incorrect pattern or an invalid payload
+/// length closes the socket. Frames are buffered in full by the codec, so
+/// `OutOfMemory` is never reported.
+pub struct TcpServer {
+    listener: TcpListener,
+    drain_timeout: Duration,
+    logical_address: [u8; 2],
+    max_sockets: u8,
+    max_payload_length: u32,
+    alive_check_timeout: Duration,
+    activation_policy: Option<Arc<dyn ActivationPolicy>>,
+    diagnostic_handler: Option<Arc<dyn DiagnosticHandler>>,
+    capture: Option<Capture>,
+    connections: Arc<Mutex<HashMap<u64, Connection>>>,
+}
+
+impl TcpServer {
+    /// Creates a new server from a listener with a drain timeout of 2 seconds,
+    /// logical address `0x0000` and up to 255 activated sockets
+    pub fn new(listener: TcpListener) -> Self {
+        TcpServer {
+            listener,
+            drain_timeout: Duration::from_secs(2),
+            logical_address: [0x00, 0x00],
+            max_sockets: u8::MAX,
+            max_payload_length: u32::MAX,
+            alive_check_timeout: Duration::from_millis(500),
+            activation_policy: None,
+            diagnostic_handler: None,
+            capture: None,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Set the time in-flight exchanges are given to complete on shutdown
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the logical address of the DoIP entity used in Routing Activation
+    /// Responses
+    pub fn logical_address(mut self, address: [u8; 2]) -> Self {
+        self.logical_address = address;
+        self
+    }
+
+    /// Set the maximum number of concurrently activated sockets
+    pub fn max_sockets(mut self, max: u8) -> Self {
+        self.max_sockets = max;
+        self
+    }
+
+    /// Set the maximum payload length of received frames, longer frames are
+    /// rejected with a Generic Nack `MessageTooLarge`
+    pub fn max_payload_length(mut self, max: u32) -> Self {
+        self.max_payload_length = max;
+        self
+    }
+
+    /// Set the time a registered connection is given to answer an Alive Check
+    /// Request before it is closed
+    pub fn alive_check_timeout(mut self, timeout: Duration) -> Self {
+        self.alive_check_timeout = timeout;
+        self
+    }
+
+    /// Set the policy deciding on Routing Activation Requests
+    pub fn activation_policy(mut self, policy: Arc<dyn ActivationPolicy>) -> Self {
+        self.activation_policy = Some(policy);
+        self
+    }
+
+    /// Set the handler answering the UDS requests of Diagnostic Messages
+    pub fn diagnostic_handler(mut self, handler: Arc<dyn DiagnosticHandler>) -> Self {
+        self.diagnostic_handler = Some(handler);
+        self
+    }
+
+    /// Record the frames of all accepted connections
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Returns the reference for the internal listener
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// Returns a handle counting the open sockets while the server runs, e.g.
+    /// to report them in an Entity Status Response
+    pub fn open_sockets(&self) -> OpenSockets {
+        OpenSockets(self.connections.clone())
+    }
+
+    /// Serve connections until `shutdown` is cancelled
+    ///
+    /// Only returns an error if accepting a connection fails, in which case all
+    /// open connections are drained as on a regular shutdown first.
+    pub async fn run(
+        self,
+        handler: Arc<dyn ServerHandler>,
+        shutdown: CancellationToken,
+    ) -> io::Result<ShutdownReport> {
+        let state = Arc::new(ServerState {
+            handler,
+            logical_address: self.logical_address,
+            max_sockets: self.max_sockets,
+            max_payload_length: self.max_payload_length,
+            alive_check_timeout: self.alive_check_timeout,
+            activation_policy: self.activation_policy,
+            diagnostic_handler: self.diagnostic_handler,
+            connections: self.connections,
+        });
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let force_closed = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = JoinSet::new();
+        let mut next_id: u64 = 0;
+        let stop = shutdown.child_token();
+        let force = CancellationToken::new();
+
+        let result = loop {
+            tokio::select! {
+                biased;
+                _ = stop.cancelled() => break Ok(()),
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                res = self.listener.accept() => {
+                    let (mut stream, peer) = match res {
+                        Ok(conn) => conn,
+                        Err(err) => break Err(err),
+                    };
+
+                    if let Some(ref capture) = self.capture {
+                        // Only fails if the connection is already gone
+                        let _ = stream.set_capture(Some(capture.clone()));
+                    }
+
+                    let id = next_id;
+                    next_id += 1;
+
+                    let (commands, rx) = mpsc::unbounded_channel();
+                    state.connections.lock().unwrap().insert(
+                        id,
+                        Connection {
+                            peer,
+                            source_address: None,
+                            busy: false,
+                            commands,
+                        },
+                    );
+
+                    let state = state.clone();
+                    let token = stop.child_token();
+                    let force = force.clone();
+                    let closed = closed.clone();
+                    let force_closed = force_closed.clone();
+
+                    tasks.spawn(async move {
+                        let interrupted =
+                            serve_connection(stream, id, peer, &state, rx, token, force).await;
+
+                        state.connections.lock().unwrap().remove(&id);
+                        match interrupted {
+                            true => force_closed.lock().unwrap().push(peer),
+                            false => closed.lock().unwrap().push(peer),
+                        }
+                    });
+                }
+            }
+        };
+
+        // Stop reading on connections which are still open, e.g. when
+        // accepting failed
+        stop.cancel();
+
+        if !drain(&mut tasks, self.drain_timeout).await {
+            // Interrupt the remaining exchanges once their current frame is
+            // written. Only connections whose peer stopped reading, so that the
+            // write cannot complete, are aborted after another drain timeout.
+            force.cancel();
+
+            if !drain(&mut tasks, self.drain_timeout).await {
+                tasks.shutdown().await;
+            }
+        }
+
+        let mut force_closed = std::mem::take(&mut *force_closed.lock().unwrap());
+        force_closed.extend(
+            state
+                .connections
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, conn)| conn.peer),
+        );
+
+        let report = ShutdownReport {
+            closed: std::mem::take(&mut *closed.lock().unwrap()),
+            force_closed,
+        };
+
+        result.map(|_| report)
+    }
+}
+
+/// Number of sockets open on a `TcpServer`, whether routing is activated on
+/// them or not
+#[derive(Clone)]
+pub struct OpenSockets(Arc<Mutex<HashMap<u64, Connection>>>);
+
+impl OpenSockets {
+    /// Returns the number of currently open sockets
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+struct ServerState {
+    handler: Arc<dyn ServerHandler>,
+    logical_address: [u8; 2],
+    max_sockets: u8,
+    max_payload_length: u32,
+    alive_check_timeout: Duration,
+    activation_policy: Option<Arc<dyn ActivationPolicy>>,
+    diagnostic_handler: Option<Arc<dyn DiagnosticHandler>>,
+    connections: Arc<Mutex<HashMap<u64, Connection>>>,
+}
+
+struct Connection {
+    peer: SocketAddr,
+    source_address: Option<[u8; 2]>,
+    busy: bool,
+    commands: mpsc::UnboundedSender<ConnectionCommand>,
+}
+
+enum ConnectionCommand {
+    AliveCheck(oneshot::Sender<()>),
+    Close,
+}
+
+impl ServerState {
+    /// Answers a Diagnostic Message with the diagnostic handler, returning the
+    /// responses with their delays
+    async fn diagnose(&self, req: DiagnosticMessage) -> Vec<(Duration, DoipPayload)> {
+        let Some(ref handler) = self.diagnostic_handler else {
+            return Vec::new();
+        };
+
+        let responses = handler
+            .handle(req.source_address, req.target_address, req.message)
+            .await;
+
+        responses
+            .into_iter()
+            .map(|res| {
+                let msg = DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                    source_address: req.target_address,
+                    target_address: req.source_address,
+                    message: res.message,
+                });
+                (res.delay, msg)
+            })
+            .collect()
+    }
+
+    /// Whether the diagnostic handler, if any, serves the target address
+    fn has_target(&self, target_address: [u8; 2]) -> bool {
+        match self.diagnostic_handler {
+            Some(ref handler) => handler.has_target(target_address),
+            None => true,
+        }
+    }
+
+    /// Whether routing is activated on the connection for the source address
+    fn is_activated(&self, id: u64, source_address: [u8; 2]) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|conn| conn.source_address == Some(source_address))
+    }
+
+    fn set_busy(&self, id: u64, busy: bool) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&id) {
+            conn.busy = busy;
+        }
+    }
+
+    async fn activate(
+        &self,
+        id: u64,
+        peer: SocketAddr,
+        req: &RoutingActivationRequest,
+    ) -> ActivationDecision {
+        let decision = match self.activation_policy {
+            Some(ref policy) => policy.check(peer, req.clone()).await,
+            None => ActivationDecision::accept(),
+        };
+
+        match decision.code {
+            ActivationCode::SuccessfullyActivated
+            | ActivationCode::ActivatedConfirmationRequired => {}
+            _ => return decision,
+        }
+
+        match self.register(id, req).await {
+            ActivationCode::SuccessfullyActivated => decision,
+            code => ActivationDecision::deny(code),
+        }
+    }
+
+    async fn register(&self, id: u64, req: &RoutingActivationRequest) -> ActivationCode {
+        // A source address registered on another socket is only taken over if
+        // that socket turns out to be dead
+        let owners = self.registered_ids(|other, conn| {
+            other != id && conn.source_address == Some(req.source_address)
+        });
+        if !owners.is_empty() {
+            self.alive_check(owners).await;
+        }
+
+        match self.try_register(id, req.source_address) {
+            Some(code) => code,
+            None => {
+                // All sockets are in use, reclaim those which are no longer
+                // alive and try again
+                self.alive_check(self.registered_ids(|_, _| true)).await;
+
+                self.try_register(id, req.source_address)
+                    .unwrap_or(ActivationCode::DeniedTCPSocketsFull)
+            }
+        }
+    }
+
+    /// Registers the source address on the connection, returns `None` if all
+    /// sockets are in use
+    fn try_register(&self, id: u64, source_address: [u8; 2]) -> Option<ActivationCode> {
+        let mut connections = self.connections.lock().unwrap();
+
+        match connections.get(&id).and_then(|conn| conn.source_address) {
+            Some(registered) if registered == source_address => {
+                return Some(ActivationCode::SuccessfullyActivated)
+            }
+            Some(_) => return Some(ActivationCode::DeniedTCPSocketAlreadyConnected),
+            None => {}
+        }
+
+        if connections
+            .values()
+            .any(|conn| conn.source_address == Some(source_address))
+        {
+            return Some(ActivationCode::DeniedSourceIsAlreadyActive);
+        }
+
+        let registered = connections
+            .values()
+            .filter(|conn| conn.source_address.is_some())
+            .count();
+
+        if registered >= self.max_sockets as usize {
+            return None;
+        }
+
+        if let Some(conn) = connections.get_mut(&id) {
+            conn.source_address = Some(source_address);
+        }
+
+        Some(ActivationCode::SuccessfullyActivated)
+    }
+
+    fn registered_ids<F>(&self, filter: F) -> Vec<u64>
+    where
+        F: Fn(u64, &Connection) -> bool,
+    {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, conn)| conn.source_address.is_some() && filter(**id, conn))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Sends an Alive Check Request on each connection and closes those which
+    /// do not respond in time
+    async fn alive_check(&self, ids: Vec<u64>) {
+        // Connections in the middle of an exchange are known to be alive
+        let checks: Vec<_> = {
+            let connections = self.connections.lock().unwrap();
+
+            ids.into_iter()
+                .filter_map(|id| connections.get(&id).map(|conn| (id, conn)))
+                .filter(|(_, conn)| !conn.busy)
+                .map(|(id, conn)| {
+                    let (tx, rx) = oneshot::channel();
+                    let sent = conn.commands.send(ConnectionCommand::AliveCheck(tx));
+                    (id, sent.ok().map(|_| rx))
+                })
+                .collect()
+        };
+
+        let results = join_all(checks.into_iter().map(|(id, rx)| async move {
+            let alive = match rx {
+                Some(rx) => matches!(
+                    tokio::time::timeout(self.alive_check_timeout, rx).await,
+                    Ok(Ok(()))
+                ),
+                None => false,
+            };
+            (id, alive)
+        }))
+        .await;
+
+        let mut connections = self.connections.lock().unwrap();
+
+        for (id, alive) in results {
+            if let (false, Some(conn)) = (alive, connections.get_mut(&id)) {
+                conn.source_address = None;
+                let _ = conn.commands.send(ConnectionCommand::Close);
+            }
+        }
+    }
+}
+
+/// Serves a connection until it is closed, returns whether an exchange was
+/// interrupted by `force`
+async fn serve_connection(
+    mut stream: TcpStream,
+    id: u64,
+    peer: SocketAddr,
+    state: &ServerState,
+    mut commands: mpsc::UnboundedReceiver<ConnectionCommand>,
+    shutdown: CancellationToken,
+    force: CancellationToken,
+) -> bool {
+    let mut alive_checks: Vec<oneshot::Sender<()>> = Vec::new();
+
+    let interrupted = 'serve: loop {
+        let msg = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break false,
+            Some(cmd) = commands.recv() => match cmd {
+                ConnectionCommand::AliveCheck(tx) => {
+                    alive_checks.push(tx);
+
+                    let req = DoipPayload::AliveCheckRequest(AliveCheckRequest {});
+                    if stream.send(req).await.is_err() {
+                        break false;
+                    }
+                    continue;
+                }
+                ConnectionCommand::Close => break false,
+            },
+            // Reading is cancellation safe as acknowledging is left to the
+            // exchange
+            msg = stream.read_frame() => match msg {
+                Some(Ok(msg)) if msg.header.payload_length > state.max_payload_length => {
+                    let nack = DoipPayload::GenericNack(GenericNack {
+                        nack_code: NackCode::MessageTooLarge,
+                    });
+                    if stream.send(nack).await.is_err() {
+                        break false;
+                    }
+                    continue;
+                }
+                Some(Ok(msg)) => msg,
+                Some(Err(CodecError::IoError(_))) | None => break false,
+                Some(Err(_)) => {
+                    let (nack_code, discard) =
+                        header_nack(stream.read_buffer(), state.max_payload_length);
+                    let nack = DoipPayload::GenericNack(GenericNack { nack_code });
+                    if stream.send(nack).await.is_err()
+                        || !discard
+                        || stream.skip_invalid_frame().await.is_err()
+                    {
+                        break false;
+                    }
+                    continue;
+                }
+            },
+        };
+
+        // The exchange is completed even if shutdown is requested meanwhile,
+        // `force` only interrupts it between frames
+        state.set_busy(id, true);
+
+        if let DoipPayload::DiagnosticMessage(ref req) = msg.payload {
+            if !state.is_activated(id, req.source_address) {
+                let nack = diagnostic_nack(req, DiagnosticNackCode::InvalidSourceAddress);
+                let _ = stream.send(nack).await;
+                break false;
+            }
+
+            // Each Diagnostic Message is answered by exactly one of ACK or NACK
+            if !state.has_target(req.target_address) {
+                let nack = diagnostic_nack(req, DiagnosticNackCode::UnknownTargetAddress);
+                if stream.send(nack).await.is_err() {
+                    break false;
+                }
+                state.set_busy(id, false);
+                continue;
+            }
+
+            if stream.send(diagnostic_ack(req)).await.is_err() {
+                break false;
+            }
+        }
+
+        let exchange = async {
+            match msg.payload {
+                DoipPayload::AliveCheckResponse(_) => {
+                    for tx in alive_checks.drain(..) {
+                        let _ = tx.send(());
+                    }
+                    (Vec::new(), false)
+                }
+                DoipPayload::DiagnosticMessage(ref req) if state.diagnostic_handler.is_some() => {
+                    (state.diagnose(req.clone()).await, false)
+                }
+                DoipPayload::RoutingActivationRequest(ref req) => {
+                    let decision = state.activate(id, peer, req).await;
+                    let close = closes_socket(&decision.code);
+
+                    let res = DoipPayload::RoutingActivationResponse(RoutingActivationResponse {
+                        logical_address: req.source_address,
+                        source_address: state.logical_address,
+                        activation_code: decision.code,
+                        buffer: decision.reserved,
+                    });
+
+                    (vec![(Duration::ZERO, res)], close)
+                }
+                _ => {
+                    let responses = state.handler.handle(peer, msg).await;
+                    (
+                        responses
+                            .into_iter()
+                            .map(|res| (Duration::ZERO, res))
+                            .collect(),
+                        false,
+                    )
+                }
+            }
+        };
+
+        let (responses, close) = tokio::select! {
+            biased;
+            _ = force.cancelled() => break true,
+            res = exchange => res,
+        };
+
+        for (delay, payload) in responses {
+            if !delay.is_zero() {
+                tokio::select! {
+                    biased;
+                    _ = force.cancelled() => break 'serve true,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            if stream.send(payload).await.is_err() {
+                break 'serve false;
+            }
+        }
+
+        state.set_busy(id, false);
+
+        if close {
+            break false;
+        }
+    };
+
+    let _ = stream.into_socket().shutdown().await;
+    interrupted
+}
+
+/// Payload types defined by ISO 13400-2
+const PAYLOAD_TYPES: [u16; 16] = [
+    0x0000, 0x0001, 0x0002, 0x0003, 0x0004, 0x0005, 0x0006, 0x0007, 0x0008, 0x4001, 0x4002, 0x4003,
+    0x4004, 0x8001, 0x8002, 0x8003,
+];
+
+/// Generic Nack for a frame the codec failed to decode, following the header
+/// checks of ISO 13400-2, and whether the frame is discarded to keep the socket
+/// open
+fn header_nack(header: &[u8], max_payload_length: u32) -> (NackCode, bool) {
+    let [version, inverse, t0, t1, l0, l1, l2, l3, ..] = *header else {
+        return (NackCode::IncorrectPatternFormat, false);
+    };
+    if inverse != !version {
+        return (NackCode::IncorrectPatternFormat, false);
+    }
+
+    let payload_type = u16::from_be_bytes([t0, t1]);
+    if !PAYLOAD_TYPES.contains(&payload_type) {
+        return (NackCode::UnknownPayloadType, true);
+    }
+    if u32::from_be_bytes([l0, l1, l2, l3]) > max_payload_length {
+        return (NackCode::MessageTooLarge, true);
+    }
+
+    // The header is valid, so the payload does not match the length required
+    // by its type
+    (NackCode::InvalidPayloadLength, false)
+}
+
+/// Negative acknowledgement of a received Diagnostic Message
+fn diagnostic_nack(req: &DiagnosticMessage, nack_code: DiagnosticNackCode) -> DoipPayload {
+    DoipPayload::DiagnosticMessageNack(DiagnosticMessageNack {
+        source_address: req.target_address,
+        target_address: req.source_address,
+        nack_code,
+        previous_message: req.message.clone(),
+    })
+}
+
+/// Waits for the connection tasks to finish, returns whether all finished in
+/// time
+async fn drain(tasks: &mut JoinSet<()>, timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await
+    .is_ok()
+}
+
+/// Whether the socket is closed after sending a Routing Activation Response
+/// with the given code
+fn closes_socket(code: &ActivationCode) -> bool {
+    !matches!(
+        code,
+        ActivationCode::SuccessfullyActivated
+            | ActivationCode::ActivatedConfirmationRequired
+            | ActivationCode::DeniedMissingAuthentication
+            | ActivationCode::DeniedRejectedConfirmation
+    )
+}
+
+#[cfg(test)]
+mod test_tcp_server {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use doip_codec::DoipCodec;
+    use doip_definitions::{
+        builder::DoipMessageBuilder,
+        message::DoipMessage,
+        payload::{
+            ActivationCode, ActivationType, AliveCheckRequest, AliveCheckResponse,
+            DiagnosticMessage, DiagnosticNackCode, DoipPayload, GenericNack, NackCode,
+            RoutingActivationRequest, RoutingActivationResponse,
+        },
+    };
+    use futures::future::BoxFuture;
+    use tokio::{io::AsyncWriteExt, time::Instant};
+    use tokio_util::{bytes::BytesMut, codec::Encoder, sync::CancellationToken};
+
+    use crate::tcp::{
+        tcp_listener::TcpListener,
+        tcp_server::{DiagnosticHandler, DiagnosticResponse, TcpServer},
+        tcp_stream::TcpStream,
+    };
+
+    fn delayed_handler(
+        delay: Duration,
+    ) -> impl Fn(SocketAddr, DoipMessage) -> futures::future::BoxFuture<'static, Vec<DoipPayload>>
+    {
+        move |_, _| {
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                vec![DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                    source_address: [0x14, 0x11],
+                })]
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drains_in_flight_exchange() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::from_millis(50)));
+        let run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let client_addr = client.get_stream_ref().local_addr().unwrap();
+        let _ = client
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shutdown.cancel();
+
+        let res = client.read().await.unwrap().unwrap();
+        assert_eq!(
+            res.payload,
+            DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                source_address: [0x14, 0x11],
+            })
+        );
+        assert!(client.read().await.is_none());
+
+        let report = run.await.unwrap().unwrap();
+        assert_eq!(report.closed, vec![client_addr]);
+        assert!(report.force_closed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_force_closes_after_drain_timeout() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            TcpServer::new(TcpListener::new(listener)).drain_timeout(Duration::from_millis(20));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::from_secs(5)));
+        let run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let client_addr = client.get_stream_ref().local_addr().unwrap();
+        let _ = client
+            .send(DoipPayload::AliveCheckRequest(AliveCheckRequest {}))
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shutdown.cancel();
+
+        let report = run.await.unwrap().unwrap();
+        assert!(report.closed.is_empty());
+        assert_eq!(report.force_closed, vec![client_addr]);
+        assert!(client.read().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interrupts_between_frames() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let slow_ecu = |_, _, _| async {
+            vec![
+                DiagnosticResponse::pending(0x31),
+                DiagnosticResponse::new(vec![0x71, 0x01, 0xff, 0x00]).after(Duration::from_secs(5)),
+            ]
+        };
+        let server = TcpServer::new(TcpListener::new(listener))
+            .drain_timeout(Duration::from_millis(20))
+            .diagnostic_handler(Arc::new(slow_ecu));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::ZERO));
+        let run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let client_addr = client.get_stream_ref().local_addr().unwrap();
+        activate(&mut client, [0x0e, 0x80]).await;
+        let _ = client
+            .send(DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                source_address: [0x0e, 0x80],
+                target_address: [0x10, 0x01],
+                message: vec![0x31, 0x01, 0xff, 0x00],
+            }))
+            .await;
+
+        // Acknowledgement and response pending arrive complete, then the
+        // connection is closed instead of waiting for the final response
+        let _ack = client.read().await.unwrap().unwrap();
+        let pending = client.read().await.unwrap().unwrap();
+        shutdown.cancel();
+        assert!(matches!(
+            pending.payload,
+            DoipPayload::DiagnosticMessage(DiagnosticMessage { ref message, .. })
+                if message[..] == [0x7f, 0x31, 0x78]
+        ));
+        assert!(client.read().await.is_none());
+
+        let report = run.await.unwrap().unwrap();
+        assert_eq!(report.force_closed, vec![client_addr]);
+    }
+
+    async fn activate(stream: &mut TcpStream, source_address: [u8; 2]) -> ActivationCode {
+        let _ = stream
+            .send(DoipPayload::RoutingActivationRequest(
+                RoutingActivationRequest {
+                    source_address,
+                    activation_type: ActivationType::Default,
+                    buffer: [0, 0, 0, 0],
+                },
+            ))
+            .await;
+
+        let res = stream.read().await.unwrap().unwrap();
+        let DoipPayload::RoutingActivationResponse(RoutingActivationResponse {
+            activation_code,
+            ..
+        }) = res.payload
+        else {
+            panic!("expected routing activation response");
+        };
+
+        activation_code
+    }
+
+    #[tokio::test]
+    async fn test_denies_when_sockets_full() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener))
+            .max_sockets(1)
+            .alive_check_timeout(Duration::from_millis(50));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::ZERO));
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut first, [0x0e, 0x80]).await,
+            ActivationCode::SuccessfullyActivated
+        );
+
+        // The first tester is alive and answers the alive check
+        let alive = tokio::spawn(async move {
+            let req = first.read().await.unwrap().unwrap();
+            assert_eq!(
+                req.payload,
+                DoipPayload::AliveCheckRequest(AliveCheckRequest {})
+            );
+            let _ = first
+                .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                    source_address: [0x0e, 0x80],
+                }))
+                .await;
+            first
+        });
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut second, [0x0e, 0x81]).await,
+            ActivationCode::DeniedTCPSocketsFull
+        );
+        assert!(second.read().await.is_none());
+
+        let _first = alive.await.unwrap();
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_evicts_dead_socket() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener))
+            .max_sockets(1)
+            .alive_check_timeout(Duration::from_millis(50));
+
+        let sockets = server.open_sockets();
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::ZERO));
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut first, [0x0e, 0x80]).await,
+            ActivationCode::SuccessfullyActivated
+        );
+        assert_eq!(sockets.count(), 1);
+
+        // The first tester never answers the alive check
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut second, [0x0e, 0x81]).await,
+            ActivationCode::SuccessfullyActivated
+        );
+
+        let req = first.read().await.unwrap().unwrap();
+        assert_eq!(
+            req.payload,
+            DoipPayload::AliveCheckRequest(AliveCheckRequest {})
+        );
+        assert!(first.read().await.is_none());
+
+        // The evicted socket is no longer counted once its task has ended
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while sockets.count() != 1 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_source_address() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener))
+            .alive_check_timeout(Duration::from_millis(50));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::ZERO));
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut first, [0x0e, 0x80]).await,
+            ActivationCode::SuccessfullyActivated
+        );
+
+        // While the first socket answers alive checks the address stays taken
+        let alive = tokio::spawn(async move {
+            let _ = first.read().await.unwrap().unwrap();
+            let _ = first
+                .send(DoipPayload::AliveCheckResponse(AliveCheckResponse {
+                    source_address: [0x0e, 0x80],
+                }))
+                .await;
+            first
+        });
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut second, [0x0e, 0x80]).await,
+            ActivationCode::DeniedSourceIsAlreadyActive
+        );
+        assert!(second.read().await.is_none());
+
+        // Once the first socket stops answering the address is handed over
+        let mut first = alive.await.unwrap();
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            activate(&mut third, [0x0e, 0x80]).await,
+            ActivationCode::SuccessfullyActivated
+        );
+
+        let req = first.read().await.unwrap().unwrap();
+        assert_eq!(
+            req.payload,
+            DoipPayload::AliveCheckRequest(AliveCheckRequest {})
+        );
+        assert!(first.read().await.is_none());
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unactivated_source() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            TcpServer::new(TcpListener::new(listener)).diagnostic_handler(Arc::new(RoutineEcu));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(delayed_handler(Duration::ZERO));
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let request = DoipPayload::DiagnosticMessage(DiagnosticMessage {
+            source_address: [0x0e, 0x80],
+            target_address: [0x10, 0x01],
+            message: vec![0x10, 0x03],
+        });
+
+        // Without routing activation and from a source address other than the
+        // activated one
+        let mut unactivated = TcpStream::connect(addr).await.unwrap();
+        let mut other_source = TcpStream::connect(addr).await.unwrap();
+        activate(&mut other_source, [0x0e, 0x81]).await;
+
+        for client in [&mut unactivated, &mut other_source] {
+            let _ = client.send(request.clone()).await;
+
+            let DoipPayload::DiagnosticMessageNack(nack) =
+                client.read().await.unwrap().unwrap().payload
+            else {
+                panic!("expected diagnostic message nack");
+            };
+            assert_eq!(nack.nack_code, DiagnosticNackCode::InvalidSourceAddress);
+            assert!(client.read().await.is_none());
+        }
+
+        shutdown.cancel();
+    }
+
+    struct RoutineEcu;
+
+    impl DiagnosticHandler for RoutineEcu {
+        fn handle(
+            &self,
+            _source: [u8; 2],
+            _target: [u8; 2],
+            message: Vec<u8>,
+        ) -> BoxFuture<'_, Vec<DiagnosticResponse>> {
+            Box::pin(async move {
+                let mut res = message.clone();
+                res[0] += 0x40;
+
+                vec![
+                    DiagnosticResponse::pending(message[0]),
+                    DiagnosticResponse::new(res).after(Duration::from_millis(50)),
+                ]
+            })
+        }
+
+        fn has_target(&self, target: [u8; 2]) -> bool {
+            target == [0x10, 0x01]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diagnostic_handler() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            TcpServer::new(TcpListener::new(listener)).diagnostic_handler(Arc::new(RoutineEcu));
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(|_, _| async { Vec::new() });
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        activate(&mut client, [0x0e, 0x80]).await;
+        let request = |target_address| {
+            DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                source_address: [0x0e, 0x80],
+                target_address,
+                message: vec![0x31, 0x01, 0xff, 0x00],
+            })
+        };
+
+        let _ = client.send(request([0x10, 0x01])).await;
+        let start = Instant::now();
+
+        let mut responses = Vec::new();
+        while responses.len() < 3 {
+            responses.push(client.read().await.unwrap().unwrap().payload);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(matches!(responses[0], DoipPayload::DiagnosticMessageAck(_)));
+        assert_eq!(
+            responses[1..],
+            [
+                DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                    source_address: [0x10, 0x01],
+                    target_address: [0x0e, 0x80],
+                    message: vec![0x7f, 0x31, 0x78],
+                }),
+                DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                    source_address: [0x10, 0x01],
+                    target_address: [0x0e, 0x80],
+                    message: vec![0x71, 0x01, 0xff, 0x00],
+                }),
+            ]
+        );
+
+        // An unknown target is only answered by a NACK and the socket stays
+        // open for the next request
+        let _ = client.send(request([0x20, 0x00])).await;
+        let DoipPayload::DiagnosticMessageNack(nack) =
+            client.read().await.unwrap().unwrap().payload
+        else {
+            panic!("expected diagnostic message nack");
+        };
+        assert_eq!(nack.nack_code, DiagnosticNackCode::UnknownTargetAddress);
+
+        let _ = client.send(request([0x10, 0x01])).await;
+        assert!(matches!(
+            client.read().await.unwrap().unwrap().payload,
+            DoipPayload::DiagnosticMessageAck(_)
+        ));
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_header_nack() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TcpServer::new(TcpListener::new(listener)).max_payload_length(16);
+
+        let shutdown = CancellationToken::new();
+        let handler = Arc::new(|_, _| async { Vec::new() });
+        let _run = tokio::spawn(server.run(handler, shutdown.clone()));
+
+        let generic_nack = |nack_code| DoipPayload::GenericNack(GenericNack { nack_code });
+
+        // A frame of an unknown payload type is discarded, the Routing
+        // Activation Request right behind it is still answered
+        let mut frames = vec![0x02, 0xfd, 0x12, 0x34, 0x00, 0x00, 0x00, 0x02, 0xaa, 0xbb];
+        let msg = DoipMessageBuilder::new()
+            .payload(DoipPayload::RoutingActivationRequest(
+                RoutingActivationRequest {
+                    source_address: [0x0e, 0x80],
+                    activation_type: ActivationType::Default,
+                    buffer: [0, 0, 0, 0],
+                },
+            ))
+            .build();
+        let mut activation = BytesMut::new();
+        DoipCodec {}.encode(msg, &mut activation).unwrap();
+        frames.extend_from_slice(&activation);
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&frames).await.unwrap();
+        let mut client = TcpStream::new(socket);
+
+        assert_eq!(
+            client.read().await.unwrap().unwrap().payload,
+            generic_nack(NackCode::UnknownPayloadType)
+        );
+        assert!(matches!(
+            client.read().await.unwrap().unwrap().payload,
+            DoipPayload::RoutingActivationResponse(_)
+        ));
+
+        // Frames longer than the maximum payload length are discarded as well
+        let request = |message| {
+            DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                source_address: [0x0e, 0x80],
+                target_address: [0x10, 0x01],
+                message,
+            })
+        };
+        let _ = client.send(request(vec![0x22; 16])).await;
+        assert_eq!(
+            client.read().await.unwrap().unwrap().payload,
+            generic_nack(NackCode::MessageTooLarge)
+        );
+
+        let _ = client.send(request(vec![0x3e, 0x00])).await;
+        assert!(matches!(
+            client.read().await.unwrap().unwrap().payload,
+            DoipPayload::DiagnosticMessageAck(_)
+        ));
+
+        // An incorrect pattern closes the socket
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(&[0x02, 0x02, 0x80, 0x01, 0x00, 0x00, 0x00, 0x00])
+            .await
+            .unwrap();
+        let mut client = TcpStream::new(socket);
+
+        assert_eq!(
+            client.read().await.unwrap().unwrap().payload,
+            generic_nack(NackCode::IncorrectPatternFormat)
+        );
+        assert!(client.read().await.is_none());
+
+        shutdown.cancel();
+    }
+}
@@ -1,12 +1,14 @@
+use std::io;
+
 use doip_codec::{DoipCodec, Error as CodecError};
 use doip_definitions::{builder::DoipMessageBuilder, message::DoipMessage, payload::DoipPayload};
 use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite};
 
-use crate::error::SocketSendError;
+use crate::{capture::StreamCapture, error::SocketSendError, trace::FrameTrace};
 
-use super::SocketConfig;
+use super::{invalid_frame_len, SocketConfig};
 
 /// Simple implementation of a TCP Stream Read Half
 ///
@@ -19,6 +21,9 @@ where
     io: FramedRead<ReadHalf<T>, DoipCodec>,
     #[allow(dead_code)]
     config: SocketConfig,
+    trace: FrameTrace,
+    capture: Option<StreamCapture>,
+    resync: bool,
 }
 
 impl<T> TcpStreamReadHalf<T>
@@ -31,12 +36,82 @@ where
         TcpStreamReadHalf {
             io,
             config: config.unwrap_or_default(),
+            trace: FrameTrace::none(),
+            capture: None,
+            resync: false,
         }
     }
 
+    /// Trace frames in the span of the stream which was split
+    pub(crate) fn traced(mut self, trace: FrameTrace) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Keep recording the frames of the stream which was split
+    pub(crate) fn captured(mut self, capture: Option<StreamCapture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
     /// Read from the stream
     pub async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
-        self.io.next().await
+        let res = match self.read_buffered().await {
+            Some(res) => Some(res),
+            None => self.io.next().await,
+        };
+        match res {
+            Some(Ok(ref msg)) => {
+                self.trace.received(msg);
+                if let Some(ref capture) = self.capture {
+                    capture.received(msg);
+                }
+            }
+            Some(Err(ref err)) => self.trace.error("Failed to read frame", err),
+            None => self.trace.closed(),
+        }
+        res
+    }
+
+    /// Removes the frame the codec failed to decode from the stream and
+    /// returns its bytes, so that reading continues with the next frame
+    ///
+    /// The codec leaves such a frame in the read buffer, the rest of its
+    /// payload is read from the stream.
+    pub(crate) async fn take_invalid_frame(&mut self) -> io::Result<Vec<u8>> {
+        let len = invalid_frame_len(self.io.read_buffer());
+        let mut chunk = [0; 4096];
+
+        while self.io.read_buffer().len() < len {
+            let n = self.io.get_mut().read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.io.read_buffer_mut().extend_from_slice(&chunk[..n]);
+        }
+
+        self.resync = true;
+        Ok(self.io.read_buffer_mut().split_to(len).to_vec())
+    }
+
+    /// Decodes the frames which were buffered behind an invalid frame, the
+    /// framed reader only returns to them once more data arrives
+    async fn read_buffered(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        if !self.resync {
+            return None;
+        }
+
+        let mut codec = DoipCodec {};
+        match codec.decode(self.io.read_buffer_mut()) {
+            Ok(None) => {
+                self.resync = false;
+                // After a decode error the framed reader ends once before it
+                // reads again
+                let _ = self.io.next().await;
+                None
+            }
+            res => res.transpose(),
+        }
     }
 }
 
@@ -50,6 +125,8 @@ where
 {
     io: FramedWrite<WriteHalf<T>, DoipCodec>,
     config: SocketConfig,
+    trace: FrameTrace,
+    capture: Option<StreamCapture>,
 }
 
 impl<T> TcpStreamWriteHalf<T>
@@ -62,19 +139,92 @@ where
         TcpStreamWriteHalf {
             io,
             config: config.unwrap_or_default(),
+            trace: FrameTrace::none(),
+            capture: None,
         }
     }
 
+    /// Trace frames in the span of the stream which was split
+    pub(crate) fn traced(mut self, trace: FrameTrace) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Keep recording the frames of the stream which was split
+    pub(crate) fn captured(mut self, capture: Option<StreamCapture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
     /// Send a message to the sink
     pub async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        self.config.check_size(&payload)?;
+
         let msg = DoipMessageBuilder::new()
             .protocol_version(self.config.protocol_version)
             .payload(payload)
             .build();
 
+        self.send_frame(msg)
+            .await
+            .map_err(SocketSendError::EncodeError)
+    }
+
+    /// Limit the size of outgoing Diagnostic Messages, e.g. to the maximum data
+    /// size reported in the entity status of the DoIP entity
+    pub fn set_max_data_size(&mut self, max: Option<u32>) {
+        self.config.max_data_size = max
+    }
+
+    /// Send a message with the protocol version of its header
+    ///
+    /// The rest of the header is derived from the payload, so that payloads
+    /// rewritten by a `ProxyFilter` are framed with their new type and length.
+    pub(crate) async fn send_message(&mut self, msg: DoipMessage) -> Result<(), CodecError> {
+        let msg = DoipMessageBuilder::new()
+            .protocol_version(msg.header.protocol_version)
+            .payload(msg.payload)
+            .build();
+
+        self.send_frame(msg).await
+    }
+
+    /// Send raw bytes, bypassing the codec
+    pub(crate) async fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        // Frames still buffered by the codec go out first
+        self.io.flush().await.map_err(|err| match err {
+            CodecError::IoError(err) => err,
+            err => io::Error::other(err.to_string()),
+        })?;
+
+        let io = self.io.get_mut();
+        io.write_all(bytes).await?;
+        io.flush().await?;
+
+        if let Some(ref capture) = self.capture {
+            capture.sent_raw(bytes);
+        }
+        Ok(())
+    }
+
+    /// Send a frame, it is only recorded once it was written
+    async fn send_frame(&mut self, msg: DoipMessage) -> Result<(), CodecError> {
+        let sent = (self.trace.enabled() || self.capture.is_some()).then(|| msg.clone());
+
         match self.io.send(msg).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(SocketSendError::EncodeError(err)),
+            Ok(_) => {
+                if let Some(msg) = sent {
+                    self.trace.sent(&msg);
+                    if let Some(capture) = &self.capture {
+                        capture.sent(&msg);
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.trace.error("Failed to send frame", &err);
+                Err(err)
+            }
         }
     }
 }
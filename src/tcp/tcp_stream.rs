@@ -2,20 +2,26 @@ use std::io::{self};
 
 use doip_codec::{DoipCodec, Error as CodecError};
 use doip_definitions::{
-    builder::DoipMessageBuilder,
-    header::ProtocolVersion,
-    message::DoipMessage,
-    payload::{DiagnosticAckCode, DiagnosticMessageAck, DoipPayload},
+    builder::DoipMessageBuilder, header::ProtocolVersion, message::DoipMessage,
+    payload::DoipPayload,
 };
 use futures::{SinkExt, StreamExt};
-use tokio::net::{TcpStream as TokioTcpStream, ToSocketAddrs};
-use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream as TokioTcpStream, ToSocketAddrs},
+};
+use tokio_util::codec::{Decoder, Framed, FramedRead, FramedWrite};
 
-use crate::error::SocketSendError;
+use crate::{
+    capture::{Capture, StreamCapture},
+    error::SocketSendError,
+    trace::FrameTrace,
+};
 
 use super::{
+    diagnostic_ack, invalid_frame_len,
     tcp_split::{TcpStreamReadHalf, TcpStreamWriteHalf},
-    SocketConfig,
+    DoipStream, SocketConfig,
 };
 /// Simple implementation of a TCP Stream
 ///
@@ -26,16 +32,24 @@ use super::{
 pub struct TcpStream {
     io: Framed<TokioTcpStream, DoipCodec>,
     config: SocketConfig,
+    /// Boxed as it is rarely set, to keep the stream small
+    capture: Option<Box<StreamCapture>>,
+    trace: FrameTrace,
+    resync: bool,
 }
 
 impl TcpStream {
     /// Creates a new TCP Stream from a Tokio TCP Stream
     pub fn new(io: TokioTcpStream) -> Self {
         TcpStream {
+            trace: FrameTrace::tcp(io.peer_addr().ok(), false),
             io: Framed::new(io, DoipCodec {}),
             config: SocketConfig {
                 protocol_version: ProtocolVersion::Iso13400_2012,
+                max_data_size: None,
             },
+            capture: None,
+            resync: false,
         }
     }
 
@@ -49,40 +63,52 @@ impl TcpStream {
 
     fn apply_codec(stream: TokioTcpStream) -> TcpStream {
         TcpStream {
+            trace: FrameTrace::tcp(stream.peer_addr().ok(), false),
             io: Framed::new(stream, DoipCodec {}),
             config: SocketConfig {
                 protocol_version: ProtocolVersion::Iso13400_2012,
+                max_data_size: None,
             },
+            capture: None,
+            resync: false,
         }
     }
 
     /// Send a DoIP frame to the sink
     pub async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        self.config.check_size(&payload)?;
+
         let msg = DoipMessageBuilder::new()
             .protocol_version(self.config.protocol_version)
             .payload(payload)
             .build();
+        let sent = (self.trace.enabled() || self.capture.is_some()).then(|| msg.clone());
 
         match self.io.send(msg).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(SocketSendError::EncodeError(err)),
+            Ok(_) => {
+                // Only frames which were written are recorded
+                if let Some(msg) = sent {
+                    self.trace.sent(&msg);
+                    if let Some(capture) = &self.capture {
+                        capture.sent(&msg);
+                    }
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.trace.error("Failed to send frame", &err);
+                Err(SocketSendError::EncodeError(err))
+            }
         }
     }
 
     /// Read a DoIP frame off the stream
     pub async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
-        let res = self.io.next().await;
+        let res = self.read_frame().await;
         if let Some(Ok(ref msg)) = res {
             if let DoipPayload::DiagnosticMessage(ref diag_msg) = msg.payload {
-                if let Err(e) = self
-                    .send(DoipPayload::DiagnosticMessageAck(DiagnosticMessageAck {
-                        source_address: diag_msg.target_address,
-                        target_address: diag_msg.source_address,
-                        ack_code: DiagnosticAckCode::Acknowledged,
-                        previous_message: diag_msg.message.clone(),
-                    }))
-                    .await
-                {
+                if let Err(e) = self.send(diagnostic_ack(diag_msg)).await {
+                    self.trace.error("Failed to send DiagnosticMessageAck", &e);
                     return Some(Err(CodecError::IoError(io::Error::new(
                         io::ErrorKind::Other,
                         format!("Failed to send DiagnosticMessageAck: {}", e),
@@ -93,12 +119,114 @@ impl TcpStream {
         res
     }
 
+    /// Read a DoIP frame without acknowledging Diagnostic Messages
+    ///
+    /// Unlike `read` this is cancellation safe, a frame is never lost when the
+    /// future is dropped.
+    pub(crate) async fn read_frame(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        let res = match self.read_buffered().await {
+            Some(res) => Some(res),
+            None => self.io.next().await,
+        };
+        match res {
+            Some(Ok(ref msg)) => {
+                self.trace.received(msg);
+                if let Some(ref capture) = self.capture {
+                    capture.received(msg);
+                }
+            }
+            Some(Err(ref err)) => self.trace.error("Failed to read frame", err),
+            None => self.trace.closed(),
+        }
+        res
+    }
+
+    /// Bytes received but not yet decoded, starting with the frame the codec
+    /// failed to decode after a read error
+    pub(crate) fn read_buffer(&self) -> &[u8] {
+        self.io.read_buffer()
+    }
+
+    /// Discards the frame the codec failed to decode, so that reading
+    /// continues with the next frame
+    ///
+    /// The codec leaves such a frame in the read buffer, the rest of its
+    /// payload is read from the stream and dropped without buffering it.
+    pub(crate) async fn skip_invalid_frame(&mut self) -> io::Result<()> {
+        let mut remaining = invalid_frame_len(self.io.read_buffer());
+        let mut chunk = [0; 4096];
+
+        loop {
+            let buf = self.io.read_buffer_mut();
+            if buf.len() >= remaining {
+                let _ = buf.split_to(remaining);
+                break;
+            }
+            remaining -= buf.len();
+            buf.clear();
+
+            let n = self.io.get_mut().read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.io.read_buffer_mut().extend_from_slice(&chunk[..n]);
+        }
+
+        self.resync = true;
+        Ok(())
+    }
+
+    /// Decodes the frames which were buffered behind an invalid frame, the
+    /// framed stream only returns to them once more data arrives
+    async fn read_buffered(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        if !self.resync {
+            return None;
+        }
+
+        let mut codec = DoipCodec {};
+        match codec.decode(self.io.read_buffer_mut()) {
+            Ok(None) => {
+                self.resync = false;
+                // After a decode error the framed stream ends once before it
+                // reads again
+                let _ = self.io.next().await;
+                None
+            }
+            res => res.transpose(),
+        }
+    }
+
     /// Converts a standard library TCP Stream to a DoIP Framed TCP Stream
     pub fn from_std(stream: std::net::TcpStream) -> io::Result<TcpStream> {
         let stream = TokioTcpStream::from_std(stream)?;
         Ok(Self::apply_codec(stream))
     }
 
+    /// Limit the size of outgoing Diagnostic Messages, e.g. to the maximum data
+    /// size reported in the entity status of the DoIP entity
+    pub fn set_max_data_size(&mut self, max: Option<u32>) {
+        self.config.max_data_size = max
+    }
+
+    /// Record the frames sent and received on this stream, or stop recording
+    /// with `None`
+    ///
+    /// Split halves keep recording.
+    pub fn set_capture(&mut self, capture: Option<Capture>) -> io::Result<()> {
+        self.capture = match capture {
+            Some(capture) => {
+                let stream = self.io.get_ref();
+                Some(Box::new(StreamCapture::new(
+                    capture,
+                    stream.local_addr()?,
+                    stream.peer_addr()?,
+                )))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
     /// Splits the TCP Stream into a Read Half and Write Half
     pub fn into_split(
         self,
@@ -114,8 +242,12 @@ impl TcpStream {
         let write = FramedWrite::new(w_half, DoipCodec {});
 
         (
-            TcpStreamReadHalf::new(read, Some(self.config)),
-            TcpStreamWriteHalf::new(write, Some(self.config)),
+            TcpStreamReadHalf::new(read, Some(self.config))
+                .traced(self.trace.clone())
+                .captured(self.capture.clone().map(|capture| *capture)),
+            TcpStreamWriteHalf::new(write, Some(self.config))
+                .traced(self.trace)
+                .captured(self.capture.map(|capture| *capture)),
         )
     }
 
@@ -130,19 +262,29 @@ impl TcpStream {
     }
 }
 
+impl DoipStream for TcpStream {
+    async fn send(&mut self, payload: DoipPayload) -> Result<(), SocketSendError> {
+        TcpStream::send(self, payload).await
+    }
+
+    async fn read(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        TcpStream::read(self).await
+    }
+}
+
 #[cfg(test)]
 mod test_tcp_stream {
     use doip_definitions::{
         builder::DoipMessageBuilder,
         payload::{
-            ActivationCode, ActivationType, DoipPayload, RoutingActivationRequest,
-            RoutingActivationResponse,
+            ActivationCode, ActivationType, DiagnosticMessage, DoipPayload,
+            RoutingActivationRequest, RoutingActivationResponse,
         },
     };
     use tokio::io::AsyncReadExt;
     use tokio_util::codec::Encoder;
 
-    use crate::tcp::tcp_stream::TcpStream;
+    use crate::{error::SocketSendError, tcp::tcp_stream::TcpStream};
 
     #[tokio::test]
     async fn test_connect() {
@@ -269,4 +411,29 @@ mod test_tcp_stream {
 
         assert_eq!(echo.payload, routing_activation_res)
     }
+
+    #[tokio::test]
+    async fn test_max_data_size() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let listener = tokio::net::TcpListener::bind(TESTER_ADDR).await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.set_max_data_size(Some(8));
+
+        let diag = |len: usize| {
+            DoipPayload::DiagnosticMessage(DiagnosticMessage {
+                source_address: [0x0e, 0x80],
+                target_address: [0x10, 0x01],
+                message: vec![0x22; len],
+            })
+        };
+
+        assert!(stream.send(diag(4)).await.is_ok());
+        assert!(matches!(
+            stream.send(diag(5)).await,
+            Err(SocketSendError::MessageTooLarge { size: 9, max: 8 })
+        ));
+    }
 }
@@ -0,0 +1,21 @@
+//! Fixtures shared by the tests of several modules
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// In-memory writer whose contents stay readable through clones, e.g. to
+/// inspect a `Capture`
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::SocketAddr;
+
+use doip_definitions::message::DoipMessage;
+
+/// Span of a socket with events for the frames passing it
+///
+/// Without the `tracing` feature this is an empty struct and all methods
+/// compile to nothing.
+#[derive(Debug, Clone)]
+pub(crate) struct FrameTrace {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+impl FrameTrace {
+    /// Span of a TCP connection, the logical addresses of tester and entity
+    /// are filled in by the Routing Activation
+    pub(crate) fn tcp(peer: Option<SocketAddr>, tls: bool) -> Self {
+        FrameTrace {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "doip_tcp",
+                peer = peer.map(tracing::field::display),
+                tls,
+                tester = tracing::field::Empty,
+                entity = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// Span of a UDP socket
+    pub(crate) fn udp(local: Option<SocketAddr>) -> Self {
+        FrameTrace {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("doip_udp", local = local.map(tracing::field::display)),
+        }
+    }
+
+    /// Frames of sockets created from split halves are traced outside of a
+    /// connection span
+    pub(crate) fn none() -> Self {
+        FrameTrace {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        }
+    }
+
+    /// Whether frames are traced at all, sockets only keep a copy of a sent
+    /// frame to trace it after the write if they are
+    pub(crate) fn enabled(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        return !self.span.is_disabled() || tracing::enabled!(tracing::Level::DEBUG);
+        #[cfg(not(feature = "tracing"))]
+        false
+    }
+
+    pub(crate) fn sent(&self, msg: &DoipMessage) {
+        self.frame("sent", msg, None)
+    }
+
+    pub(crate) fn received(&self, msg: &DoipMessage) {
+        self.frame("received", msg, None)
+    }
+
+    pub(crate) fn sent_to(&self, msg: &DoipMessage, peer: SocketAddr) {
+        self.frame("sent", msg, Some(peer))
+    }
+
+    pub(crate) fn received_from(&self, msg: &DoipMessage, peer: SocketAddr) {
+        self.frame("received", msg, Some(peer))
+    }
+
+    /// A frame could not be encoded, decoded or acknowledged
+    pub(crate) fn error(&self, msg: &str, err: &dyn std::fmt::Display) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(parent: &self.span, error = %err, "{}", msg);
+    }
+
+    /// The peer closed the connection
+    pub(crate) fn closed(&self) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, "Connection closed");
+    }
+
+    fn frame(&self, direction: &str, msg: &DoipMessage, peer: Option<SocketAddr>) {
+        #[cfg(feature = "tracing")]
+        {
+            use doip_definitions::payload::DoipPayload;
+            use tracing::field::display;
+
+            match msg.payload {
+                DoipPayload::RoutingActivationRequest(ref req) => {
+                    self.span
+                        .record("tester", display(Address(req.source_address)));
+                }
+                DoipPayload::RoutingActivationResponse(ref res) => {
+                    self.span
+                        .record("tester", display(Address(res.logical_address)));
+                    self.span
+                        .record("entity", display(Address(res.source_address)));
+                }
+                _ => {}
+            }
+
+            tracing::debug!(
+                parent: &self.span,
+                direction,
+                peer = peer.map(display),
+                payload_type = ?msg.header.payload_type,
+                length = msg.header.payload_length,
+                "DoIP frame"
+            );
+
+            if tracing::enabled!(tracing::Level::TRACE) {
+                if let Some(bytes) = crate::capture::encode(msg) {
+                    tracing::trace!(
+                        parent: &self.span,
+                        direction,
+                        payload = %Hex(&bytes[8..]),
+                        "DoIP payload"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Logical address as 4 hex digits
+#[cfg(feature = "tracing")]
+struct Address([u8; 2]);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02X}{:02X}", self.0[0], self.0[1])
+    }
+}
+
+/// Bytes as space separated hex
+#[cfg(feature = "tracing")]
+struct Hex<'a>(&'a [u8]);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test_trace {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use doip_definitions::{
+        builder::DoipMessageBuilder,
+        payload::{ActivationType, DoipPayload, RoutingActivationRequest},
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    use super::FrameTrace;
+
+    /// Collects the fields of spans and events as `name=value`
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Collector {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            span.record(&mut self.clone());
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, values: &span::Record<'_>) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            event.record(&mut self.clone());
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn test_frame_events() {
+        let collector = Collector::default();
+        let fields = collector.0.clone();
+
+        tracing::subscriber::with_default(collector, || {
+            let trace = FrameTrace::tcp(Some("127.0.0.1:13400".parse().unwrap()), true);
+            let msg = DoipMessageBuilder::new()
+                .payload(DoipPayload::RoutingActivationRequest(
+                    RoutingActivationRequest {
+                        source_address: [0x0e, 0x80],
+                        activation_type: ActivationType::Default,
+                        buffer: [0, 0, 0, 0],
+                    },
+                ))
+                .build();
+            trace.sent(&msg);
+        });
+
+        let fields = fields.lock().unwrap();
+        for field in [
+            "peer=127.0.0.1:13400",
+            "tls=true",
+            "tester=0E80",
+            "direction=\"sent\"",
+            "payload=0E 80 00 00 00 00 00",
+        ] {
+            assert!(
+                fields.iter().any(|f| f == field),
+                "{} in {:?}",
+                field,
+                fields
+            );
+        }
+    }
+}
@@ -1,12 +1,20 @@
 use doip_definitions::payload::{
-    EntityStatusRequest, EntityStatusResponse, GenericNack, PowerInformationRequest,
+    DoipPayload, EntityStatusRequest, EntityStatusResponse, GenericNack, PowerInformationRequest,
     PowerInformationResponse, VehicleAnnouncementMessage, VehicleIdentificationRequest,
     VehicleIdentificationRequestEid, VehicleIdentificationRequestVin,
 };
 
+mod udp_discovery;
+mod udp_handle;
+mod udp_relay;
 mod udp_socket;
+mod udp_status;
 
+pub use crate::udp::udp_discovery::*;
+pub use crate::udp::udp_handle::*;
+pub use crate::udp::udp_relay::*;
 pub use crate::udp::udp_socket::*;
+pub use crate::udp::udp_status::*;
 
 /// Helper Trait which assists in applying LSP hints to the send and receive of
 /// sockets.
@@ -21,3 +29,42 @@ impl DoipUdpPayload for EntityStatusRequest {}
 impl DoipUdpPayload for EntityStatusResponse {}
 impl DoipUdpPayload for PowerInformationRequest {}
 impl DoipUdpPayload for PowerInformationResponse {}
+
+/// UDP request payload with a known response type, used by
+/// `UdpSocket::request`
+pub trait DoipUdpRequest: DoipUdpPayload {
+    /// Payload expected in response to the request
+    type Response;
+
+    /// Wrap the request in a `DoipPayload`
+    fn into_payload(self) -> DoipPayload;
+
+    /// Extract the response from a received payload, returns `None` if the
+    /// payload is not a response to this request
+    fn response(payload: DoipPayload) -> Option<Self::Response>;
+}
+
+macro_rules! impl_udp_request {
+    ($request:ident => $response:ident) => {
+        impl DoipUdpRequest for $request {
+            type Response = $response;
+
+            fn into_payload(self) -> DoipPayload {
+                DoipPayload::$request(self)
+            }
+
+            fn response(payload: DoipPayload) -> Option<Self::Response> {
+                match payload {
+                    DoipPayload::$response(res) => Some(res),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_udp_request!(VehicleIdentificationRequest => VehicleAnnouncementMessage);
+impl_udp_request!(VehicleIdentificationRequestEid => VehicleAnnouncementMessage);
+impl_udp_request!(VehicleIdentificationRequestVin => VehicleAnnouncementMessage);
+impl_udp_request!(EntityStatusRequest => EntityStatusResponse);
+impl_udp_request!(PowerInformationRequest => PowerInformationResponse);
@@ -0,0 +1,270 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use doip_codec::Error as CodecError;
+use doip_definitions::payload::{
+    DoipPayload, VehicleAnnouncementMessage, VehicleIdentificationRequest,
+};
+use futures::future::join_all;
+use if_addrs::IfAddr;
+use tokio::time::Instant;
+
+use super::UdpSocket;
+
+/// UDP port DoIP entities listen on for Vehicle Identification Requests
+pub const DOIP_UDP_DISCOVERY_PORT: u16 = 13400;
+
+/// A local IPv4 interface used to send discovery requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastInterface {
+    /// Name of the interface, e.g. `eth0`
+    pub name: String,
+    /// Local address of the interface
+    pub addr: Ipv4Addr,
+    /// Directed broadcast address of the interface's subnet
+    pub broadcast: Ipv4Addr,
+}
+
+impl BroadcastInterface {
+    /// Creates an interface description from its address and netmask
+    pub fn new(name: impl Into<String>, addr: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        BroadcastInterface {
+            name: name.into(),
+            addr,
+            broadcast: directed_broadcast(addr, netmask),
+        }
+    }
+}
+
+/// Computes the directed broadcast address of the subnet `addr` belongs to
+pub fn directed_broadcast(addr: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(addr) | !u32::from(netmask))
+}
+
+/// Configuration of a discovery run
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Names of the interfaces to send on, `None` uses all non-loopback IPv4
+    /// interfaces
+    pub interfaces: Option<Vec<String>>,
+    /// Destination port of the requests
+    pub port: u16,
+    /// Time to collect responses for
+    pub timeout: Duration,
+    /// Request sent on each interface, one of the Vehicle Identification
+    /// Request variants
+    pub request: DoipPayload,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            interfaces: None,
+            port: DOIP_UDP_DISCOVERY_PORT,
+            timeout: Duration::from_secs(2),
+            request: DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {}),
+        }
+    }
+}
+
+/// A DoIP entity which answered a discovery request
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredEntity {
+    /// Name of the interface the response was received on
+    pub interface: String,
+    /// Address the response was sent from
+    pub addr: SocketAddr,
+    /// Vehicle Announcement Message sent by the entity
+    pub announcement: VehicleAnnouncementMessage,
+}
+
+impl UdpSocket {
+    /// Lists the local IPv4 interfaces usable for discovery
+    ///
+    /// Loopback interfaces are skipped. If `names` is given only interfaces
+    /// with a matching name are returned.
+    pub fn broadcast_interfaces(names: Option<&[String]>) -> io::Result<Vec<BroadcastInterface>> {
+        let interfaces = if_addrs::get_if_addrs()?
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter(|iface| match names {
+                Some(names) => names.contains(&iface.name),
+                None => true,
+            })
+            .filter_map(|iface| match iface.addr {
+                IfAddr::V4(v4) => Some(BroadcastInterface::new(iface.name, v4.ip, v4.netmask)),
+                IfAddr::V6(_) => None,
+            })
+            .collect();
+
+        Ok(interfaces)
+    }
+
+    /// Sends the discovery request on each selected interface and collects
+    /// the Vehicle Announcement Messages received until the timeout
+    pub async fn discover(config: &DiscoveryConfig) -> io::Result<Vec<DiscoveredEntity>> {
+        let interfaces = Self::broadcast_interfaces(config.interfaces.as_deref())?;
+
+        if interfaces.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No matching IPv4 interface found",
+            ));
+        }
+
+        Self::discover_on(&interfaces, config).await
+    }
+
+    /// Sends the discovery request to the broadcast address of each given
+    /// interface and collects the Vehicle Announcement Messages received until
+    /// the timeout
+    ///
+    /// Responses are merged, an entity which answers on several interfaces is
+    /// reported once per interface.
+    pub async fn discover_on(
+        interfaces: &[BroadcastInterface],
+        config: &DiscoveryConfig,
+    ) -> io::Result<Vec<DiscoveredEntity>> {
+        let deadline = Instant::now() + config.timeout;
+
+        let results = join_all(
+            interfaces
+                .iter()
+                .map(|iface| discover_interface(iface, config, deadline)),
+        )
+        .await;
+
+        let mut entities: Vec<DiscoveredEntity> = Vec::new();
+
+        for result in results {
+            for entity in result? {
+                if !entities.contains(&entity) {
+                    entities.push(entity);
+                }
+            }
+        }
+
+        Ok(entities)
+    }
+}
+
+async fn discover_interface(
+    iface: &BroadcastInterface,
+    config: &DiscoveryConfig,
+    deadline: Instant,
+) -> io::Result<Vec<DiscoveredEntity>> {
+    // Binding to the interface address makes the request leave on that
+    // interface rather than the one of the default route
+    let mut socket = UdpSocket::bind(SocketAddrV4::new(iface.addr, 0)).await?;
+    socket.get_socket_ref().set_broadcast(true)?;
+
+    let target = SocketAddr::V4(SocketAddrV4::new(iface.broadcast, config.port));
+    socket
+        .send(config.request.clone(), target)
+        .await
+        .map_err(|err| match err {
+            CodecError::IoError(err) => err,
+            err => io::Error::other(err.to_string()),
+        })?;
+
+    let mut entities = Vec::new();
+
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv()).await {
+        match res {
+            Some(Ok((msg, addr))) => {
+                if let DoipPayload::VehicleAnnouncementMessage(announcement) = msg.payload {
+                    entities.push(DiscoveredEntity {
+                        interface: iface.name.clone(),
+                        addr,
+                        announcement,
+                    });
+                }
+            }
+            // Malformed datagrams from other hosts do not end the discovery
+            Some(Err(CodecError::IoError(err))) => return Err(err),
+            Some(Err(_)) => {}
+            None => break,
+        }
+    }
+
+    Ok(entities)
+}
+
+#[cfg(test)]
+mod test_udp_discovery {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use doip_definitions::payload::{
+        ActionCode, DoipPayload, VehicleAnnouncementMessage, VehicleIdentificationRequest,
+    };
+
+    use crate::udp::{
+        udp_discovery::{directed_broadcast, BroadcastInterface, DiscoveryConfig},
+        UdpSocket,
+    };
+
+    #[test]
+    fn test_directed_broadcast() {
+        assert_eq!(
+            directed_broadcast(
+                Ipv4Addr::new(192, 168, 10, 23),
+                Ipv4Addr::new(255, 255, 255, 0)
+            ),
+            Ipv4Addr::new(192, 168, 10, 255)
+        );
+        assert_eq!(
+            directed_broadcast(Ipv4Addr::new(169, 254, 3, 7), Ipv4Addr::new(255, 255, 0, 0)),
+            Ipv4Addr::new(169, 254, 255, 255)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_on() {
+        const ENTITY_ADDR: &str = "127.0.0.1:0";
+
+        let mut entity = UdpSocket::bind(ENTITY_ADDR).await.unwrap();
+        let port = entity.get_socket_ref().local_addr().unwrap().port();
+
+        let announcement = VehicleAnnouncementMessage {
+            vin: *b"WVWZZZ1JZXW000001",
+            logical_address: [0x10, 0x01],
+            eid: [0, 1, 2, 3, 4, 5],
+            gid: [0, 1, 2, 3, 4, 5],
+            further_action: ActionCode::NoFurtherActionRequired,
+            vin_gid_sync: None,
+        };
+
+        let responder = {
+            let announcement = announcement.clone();
+            tokio::spawn(async move {
+                let (req, tester) = entity.recv().await.unwrap().unwrap();
+                assert_eq!(
+                    req.payload,
+                    DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {})
+                );
+
+                let res = DoipPayload::VehicleAnnouncementMessage(announcement);
+                entity.send(res, tester).await.unwrap();
+            })
+        };
+
+        // The loopback interface stands in for a bench network
+        let lo =
+            BroadcastInterface::new("lo", Ipv4Addr::LOCALHOST, Ipv4Addr::new(255, 255, 255, 255));
+        let config = DiscoveryConfig {
+            port,
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+
+        let entities = UdpSocket::discover_on(&[lo], &config).await.unwrap();
+        responder.await.unwrap();
+
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].interface, "lo");
+        assert_eq!(entities[0].announcement, announcement);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use doip_codec::{DoipCodec, Error as CodecError};
+use doip_definitions::{
+    builder::DoipMessageBuilder, header::ProtocolVersion, message::DoipMessage,
+    payload::DoipPayload,
+};
+use tokio::net::UdpSocket as TokioUdpSocket;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder},
+};
+
+use crate::{trace::FrameTrace, Capture, SocketConfig};
+
+/// Largest UDP datagram which can be received
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Cloneable handle to a UDP Socket with DoIP Frames
+///
+/// Unlike `UdpSocket` all methods take `&self`, each datagram is decoded on
+/// its own. Clones share the same socket so one task can listen for Vehicle
+/// Announcement Messages while others send requests.
+#[derive(Debug, Clone)]
+pub struct UdpSocketHandle {
+    io: Arc<TokioUdpSocket>,
+    config: SocketConfig,
+    capture: Option<Capture>,
+    trace: FrameTrace,
+}
+
+impl UdpSocketHandle {
+    /// Creates a new handle from a Tokio UDP Socket
+    pub fn new(io: TokioUdpSocket) -> Self {
+        Self::with_config(io, SocketConfig::default())
+    }
+
+    pub(crate) fn with_config(io: TokioUdpSocket, config: SocketConfig) -> Self {
+        UdpSocketHandle {
+            trace: FrameTrace::udp(io.local_addr().ok()),
+            io: Arc::new(io),
+            config,
+            capture: None,
+        }
+    }
+
+    /// Receive a DoIP Frame from the socket queue
+    ///
+    /// A datagram which does not hold a complete DoIP Frame is returned as an
+    /// error, the next call continues with the following datagram.
+    pub async fn recv(&self) -> Result<(DoipMessage, SocketAddr), CodecError> {
+        let res = self.recv_frame().await;
+        match res {
+            Ok((ref msg, from)) => self.trace.received_from(msg, from),
+            Err(ref err) => self.trace.error("Failed to receive datagram", err),
+        }
+        res
+    }
+
+    async fn recv_frame(&self) -> Result<(DoipMessage, SocketAddr), CodecError> {
+        let mut buf = BytesMut::zeroed(MAX_DATAGRAM_SIZE);
+        let (len, addr) = self.io.recv_from(&mut buf).await?;
+        buf.truncate(len);
+
+        let mut codec = DoipCodec {};
+        match codec.decode(&mut buf)? {
+            Some(msg) => {
+                if let Some(ref capture) = self.capture {
+                    capture.record_udp(addr, self.io.local_addr()?, &msg);
+                }
+                Ok((msg, addr))
+            }
+            None => Err(CodecError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Datagram does not contain a complete DoIP frame",
+            ))),
+        }
+    }
+
+    /// Send a DoIP Frame
+    pub async fn send(&self, payload: DoipPayload, addr: SocketAddr) -> Result<(), CodecError> {
+        let msg = DoipMessageBuilder::new()
+            .protocol_version(self.config.protocol_version)
+            .payload(payload)
+            .build();
+
+        self.send_message(msg, addr).await
+    }
+
+    /// Send a complete DoIP Frame, keeping its header
+    pub(crate) async fn send_message(
+        &self,
+        msg: DoipMessage,
+        addr: SocketAddr,
+    ) -> Result<(), CodecError> {
+        let sent = (self.trace.enabled() || self.capture.is_some()).then(|| msg.clone());
+
+        let mut codec = DoipCodec {};
+        let mut buf = BytesMut::new();
+        if let Err(err) = codec.encode(msg, &mut buf) {
+            self.trace.error("Failed to encode datagram", &err);
+            return Err(err);
+        }
+
+        self.io.send_to(&buf, addr).await?;
+
+        if let Some(msg) = sent {
+            self.trace.sent_to(&msg, addr);
+            if let Some(ref capture) = self.capture {
+                if let Ok(local) = self.io.local_addr() {
+                    capture.record_udp(local, addr, &msg);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a reference to the inner Tokio UDP Socket
+    pub fn get_socket_ref(&self) -> &TokioUdpSocket {
+        &self.io
+    }
+
+    /// Change the protocol version used by this handle
+    ///
+    /// Only affects this handle and clones made from it afterwards.
+    pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
+        self.config.protocol_version = version
+    }
+
+    /// Record the frames sent and received on this handle, or stop recording
+    /// with `None`
+    ///
+    /// Only affects this handle and clones made from it afterwards.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture
+    }
+}
+
+#[cfg(test)]
+mod test_udp_handle {
+    use doip_definitions::payload::{
+        DoipPayload, EntityStatusRequest, PowerInformationRequest, VehicleIdentificationRequest,
+    };
+
+    use crate::udp::UdpSocket;
+
+    #[tokio::test]
+    async fn test_concurrent_send_recv() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let tester = UdpSocket::bind(TESTER_ADDR).await.unwrap().into_handle();
+        let tester_addr = tester.get_socket_ref().local_addr().unwrap();
+        let entity = UdpSocket::bind(TESTER_ADDR).await.unwrap().into_handle();
+        let entity_addr = entity.get_socket_ref().local_addr().unwrap();
+
+        // Listen on one clone while sending on another
+        let listener = {
+            let tester = tester.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                for _ in 0..2 {
+                    received.push(tester.recv().await.unwrap().0.payload);
+                }
+                received
+            })
+        };
+
+        tester
+            .send(
+                DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {}),
+                entity_addr,
+            )
+            .await
+            .unwrap();
+        let (req, from) = entity.recv().await.unwrap();
+        assert_eq!(from, tester_addr);
+        assert_eq!(
+            req.payload,
+            DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {})
+        );
+
+        let responses = [
+            DoipPayload::EntityStatusRequest(EntityStatusRequest {}),
+            DoipPayload::PowerInformationRequest(PowerInformationRequest {}),
+        ];
+        for payload in responses.iter().cloned() {
+            entity.send(payload, tester_addr).await.unwrap();
+        }
+
+        assert_eq!(listener.await.unwrap(), responses);
+    }
+}
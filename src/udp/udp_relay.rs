@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+
+use tokio_util::sync::CancellationToken;
+
+use super::{UdpSocket, UdpSocketHandle};
+
+/// Relays UDP discovery between testers and a DoIP entity
+///
+/// Requests received on the local socket, such as Vehicle Identification or
+/// Entity Status Requests, are forwarded to the upstream address, which may be
+/// a single entity or a broadcast address. Responses arriving on the upstream
+/// socket are sent back to the tester which made the last request. Frames are
+/// forwarded with their header, including the protocol version. Together with a
+/// `TcpProxy` on the same local address, testers discover and connect to the
+/// proxy as if it were the entity.
+///
+/// DoIP responses carry nothing to match them to a request, so when several
+/// testers discover at the same time, responses may go to the wrong tester.
+pub struct UdpRelay {
+    local: UdpSocketHandle,
+    upstream: UdpSocketHandle,
+    upstream_addr: SocketAddr,
+}
+
+impl UdpRelay {
+    /// Creates a new relay between a socket facing the testers and a socket
+    /// facing the entity at `upstream_addr`
+    ///
+    /// Broadcast has to be enabled on the upstream socket if `upstream_addr` is
+    /// a broadcast address.
+    pub fn new(local: UdpSocket, upstream: UdpSocket, upstream_addr: SocketAddr) -> Self {
+        UdpRelay {
+            local: local.into_handle(),
+            upstream: upstream.into_handle(),
+            upstream_addr,
+        }
+    }
+
+    /// Relay datagrams until `shutdown` is cancelled
+    ///
+    /// Datagrams which are not valid DoIP frames are dropped.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let mut tester = None;
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => return,
+                res = self.local.recv() => {
+                    let Ok((msg, from)) = res else { continue };
+
+                    tester = Some(from);
+                    let _ = self.upstream.send_message(msg, self.upstream_addr).await;
+                }
+                res = self.upstream.recv() => {
+                    let (Ok((msg, _)), Some(tester)) = (res, tester) else { continue };
+
+                    let _ = self.local.send_message(msg, tester).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_udp_relay {
+    use doip_definitions::{
+        header::ProtocolVersion,
+        payload::{
+            ActionCode, DoipPayload, VehicleAnnouncementMessage, VehicleIdentificationRequest,
+        },
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::udp::{UdpRelay, UdpSocket};
+
+    #[tokio::test]
+    async fn test_relays_discovery() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let mut entity = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let entity_addr = entity.get_socket_ref().local_addr().unwrap();
+
+        let local = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let relay_addr = local.get_socket_ref().local_addr().unwrap();
+        let upstream = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let relay = UdpRelay::new(local, upstream, entity_addr);
+
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(relay.run(shutdown.clone()));
+
+        let mut tester = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        tester.set_protocol_version(ProtocolVersion::Iso13400_2012);
+        let _ = tester
+            .send(
+                DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {}),
+                relay_addr,
+            )
+            .await;
+
+        let (req, from) = entity.recv().await.unwrap().unwrap();
+        assert_eq!(req.header.protocol_version, ProtocolVersion::Iso13400_2012);
+        assert_eq!(
+            req.payload,
+            DoipPayload::VehicleIdentificationRequest(VehicleIdentificationRequest {})
+        );
+
+        let announcement = DoipPayload::VehicleAnnouncementMessage(VehicleAnnouncementMessage {
+            vin: *b"WDD2221761A000001",
+            logical_address: [0x10, 0x00],
+            eid: [0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e],
+            gid: [0x00, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e],
+            further_action: ActionCode::NoFurtherActionRequired,
+            vin_gid_sync: None,
+        });
+        entity.set_protocol_version(ProtocolVersion::Iso13400_2019);
+        let _ = entity.send(announcement.clone(), from).await;
+
+        let (res, from) = tester.recv().await.unwrap().unwrap();
+        assert_eq!(from, relay_addr);
+        assert_eq!(res.header.protocol_version, ProtocolVersion::Iso13400_2019);
+        assert_eq!(res.payload, announcement);
+
+        shutdown.cancel();
+        run.await.unwrap();
+    }
+}
@@ -1,14 +1,16 @@
-use crate::SocketConfig;
+use crate::{error::UdpRequestError, trace::FrameTrace, Capture, SocketConfig};
 use doip_codec::{DoipCodec, Error as CodecError};
 use doip_definitions::{
     builder::DoipMessageBuilder, header::ProtocolVersion, message::DoipMessage,
     payload::DoipPayload,
 };
 use futures::{SinkExt, StreamExt};
-use std::{io, net::SocketAddr};
+use std::{io, net::SocketAddr, time::Duration};
 use tokio::net::{ToSocketAddrs, UdpSocket as TokioUdpSocket};
 use tokio_util::udp::UdpFramed;
 
+use super::{DoipUdpRequest, UdpSocketHandle};
+
 /// Simple implementation of a UDP Socket with DoIP Frames
 ///
 /// Applying only the most simple methods on this struct it is able to act as
@@ -17,6 +19,8 @@ use tokio_util::udp::UdpFramed;
 pub struct UdpSocket {
     io: UdpFramed<DoipCodec, TokioUdpSocket>,
     config: SocketConfig,
+    capture: Option<Capture>,
+    trace: FrameTrace,
 }
 
 impl UdpSocket {
@@ -26,8 +30,10 @@ impl UdpSocket {
         let sock = TokioUdpSocket::from_std(sock)?;
 
         Ok(UdpSocket {
+            trace: FrameTrace::udp(sock.local_addr().ok()),
             io: UdpFramed::new(sock, DoipCodec {}),
             config: SocketConfig::default(),
+            capture: None,
         })
     }
 
@@ -36,8 +42,10 @@ impl UdpSocket {
         let sock = TokioUdpSocket::bind(addr).await?;
 
         Ok(UdpSocket {
+            trace: FrameTrace::udp(sock.local_addr().ok()),
             io: UdpFramed::new(sock, DoipCodec {}),
             config: SocketConfig::default(),
+            capture: None,
         })
     }
 
@@ -48,7 +56,18 @@ impl UdpSocket {
 
     /// Receive a DoIP Frame from the socket queue
     pub async fn recv(&mut self) -> Option<Result<(DoipMessage, SocketAddr), CodecError>> {
-        self.io.next().await
+        let res = self.io.next().await;
+        match res {
+            Some(Ok((ref msg, from))) => self.trace.received_from(msg, from),
+            Some(Err(ref err)) => self.trace.error("Failed to receive datagram", err),
+            None => {}
+        }
+        if let (Some(Ok((ref msg, from))), Some(ref capture)) = (&res, &self.capture) {
+            if let Ok(local) = self.io.get_ref().local_addr() {
+                capture.record_udp(*from, local, msg);
+            }
+        }
+        res
     }
 
     /// Send a DoIP Frame
@@ -57,7 +76,94 @@ impl UdpSocket {
             .protocol_version(self.config.protocol_version)
             .payload(payload)
             .build();
-        self.io.send((msg, addr)).await
+
+        let sent = (self.trace.enabled() || self.capture.is_some()).then(|| msg.clone());
+        self.io.send((msg, addr)).await.inspect_err(|err| {
+            self.trace.error("Failed to send datagram", err);
+        })?;
+
+        if let Some(msg) = sent {
+            self.trace.sent_to(&msg, addr);
+            if let Some(ref capture) = self.capture {
+                if let Ok(local) = self.io.get_ref().local_addr() {
+                    capture.record_udp(local, addr, &msg);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the peer the socket is connected to
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().peer_addr()
+    }
+
+    /// Send a DoIP Frame to the connected peer
+    ///
+    /// Fails with `io::ErrorKind::NotConnected` if `connect` was not called.
+    pub async fn send_connected(&mut self, payload: DoipPayload) -> Result<(), CodecError> {
+        let addr = self.peer_addr()?;
+        self.send(payload, addr).await
+    }
+
+    /// Receive a DoIP Frame from the connected peer
+    ///
+    /// Fails with `io::ErrorKind::NotConnected` if `connect` was not called.
+    pub async fn recv_connected(&mut self) -> Option<Result<DoipMessage, CodecError>> {
+        if let Err(err) = self.peer_addr() {
+            return Some(Err(err.into()));
+        }
+
+        // The OS only delivers datagrams from the connected peer
+        self.recv().await.map(|res| res.map(|(msg, _)| msg))
+    }
+
+    /// Send a request and wait for the matching response from the peer
+    ///
+    /// Datagrams from other addresses and unrelated payloads are discarded.
+    /// If no response arrives within `timeout` the request is sent again, up
+    /// to `retries` times. A Generic DoIP Header Negative Acknowledge from the
+    /// peer is returned as `UdpRequestError::Nack`.
+    pub async fn request<R: DoipUdpRequest>(
+        &mut self,
+        request: R,
+        addr: SocketAddr,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<R::Response, UdpRequestError> {
+        let payload = request.into_payload();
+        let mut attempts = 0;
+
+        while attempts <= retries {
+            attempts += 1;
+            self.send(payload.clone(), addr).await?;
+
+            let deadline = tokio::time::Instant::now() + timeout;
+
+            while let Ok(res) = tokio::time::timeout_at(deadline, self.recv()).await {
+                let (msg, from) = match res {
+                    Some(Ok(res)) => res,
+                    Some(Err(CodecError::IoError(err))) => return Err(err.into()),
+                    // Malformed datagrams are dropped like lost ones
+                    Some(Err(_)) => continue,
+                    None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                };
+
+                if from != addr {
+                    continue;
+                }
+
+                if let DoipPayload::GenericNack(nack) = msg.payload {
+                    return Err(UdpRequestError::Nack(nack.nack_code));
+                }
+
+                if let Some(res) = R::response(msg.payload) {
+                    return Ok(res);
+                }
+            }
+        }
+
+        Err(UdpRequestError::Timeout { attempts })
     }
 
     /// Get a reference to the inner Tokio UDP Socket
@@ -70,21 +176,42 @@ impl UdpSocket {
         self.io.into_inner()
     }
 
+    /// Converts the socket into a cloneable handle, frames which were already
+    /// read into the buffer of the socket are discarded
+    pub fn into_handle(self) -> UdpSocketHandle {
+        let mut handle = UdpSocketHandle::with_config(self.io.into_inner(), self.config);
+        handle.set_capture(self.capture);
+        handle
+    }
+
     /// Change the protocol version on the socket
     pub fn set_protocol_version(&mut self, version: ProtocolVersion) {
         self.config.protocol_version = version
     }
+
+    /// Record the frames sent and received on this socket, or stop recording
+    /// with `None`
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture
+    }
 }
 
 #[cfg(test)]
 mod test_udp_socket {
-    use std::net::ToSocketAddrs;
+    use std::{io, net::ToSocketAddrs, time::Duration};
+
+    use doip_codec::Error as CodecError;
 
     use doip_definitions::{
         header::PayloadType,
-        payload::{DoipPayload, VehicleIdentificationRequest},
+        payload::{
+            DoipPayload, EntityStatusRequest, EntityStatusResponse, GenericNack, NackCode,
+            NodeType, PowerInformationRequest, VehicleIdentificationRequest,
+        },
     };
 
+    use crate::error::UdpRequestError;
+
     use super::UdpSocket;
 
     #[tokio::test]
@@ -108,4 +235,132 @@ mod test_udp_socket {
         assert!(res.header.payload_length == 0);
         assert!(addr == TESTER_ADDR1.to_socket_addrs().unwrap().next().unwrap());
     }
+
+    #[tokio::test]
+    async fn test_request_retries() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let mut tester = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let mut entity = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let entity_addr = entity.get_socket_ref().local_addr().unwrap();
+
+        let status = EntityStatusResponse {
+            node_type: NodeType::DoipNode,
+            max_concurrent_sockets: [4],
+            currently_open_sockets: [1],
+            max_data_size: [0x00, 0x00, 0x0f, 0xff],
+        };
+
+        let responder = {
+            let status = status.clone();
+            tokio::spawn(async move {
+                // The first request is lost
+                let _ = entity.recv().await.unwrap().unwrap();
+
+                let (req, tester) = entity.recv().await.unwrap().unwrap();
+                assert_eq!(
+                    req.payload,
+                    DoipPayload::EntityStatusRequest(EntityStatusRequest {})
+                );
+                entity
+                    .send(DoipPayload::EntityStatusResponse(status), tester)
+                    .await
+                    .unwrap();
+
+                let (_, tester) = entity.recv().await.unwrap().unwrap();
+                entity
+                    .send(
+                        DoipPayload::GenericNack(GenericNack {
+                            nack_code: NackCode::UnknownPayloadType,
+                        }),
+                        tester,
+                    )
+                    .await
+                    .unwrap();
+            })
+        };
+
+        let res = tester
+            .request(
+                EntityStatusRequest {},
+                entity_addr,
+                Duration::from_millis(50),
+                1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, status);
+
+        let res = tester
+            .request(
+                PowerInformationRequest {},
+                entity_addr,
+                Duration::from_millis(50),
+                0,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(UdpRequestError::Nack(NackCode::UnknownPayloadType))
+        ));
+
+        responder.await.unwrap();
+
+        let res = tester
+            .request(
+                PowerInformationRequest {},
+                entity_addr,
+                Duration::from_millis(10),
+                2,
+            )
+            .await;
+        assert!(matches!(res, Err(UdpRequestError::Timeout { attempts: 3 })));
+    }
+
+    #[tokio::test]
+    async fn test_connected() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let mut tester = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let mut entity = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let tester_addr = tester.get_socket_ref().local_addr().unwrap();
+        let entity_addr = entity.get_socket_ref().local_addr().unwrap();
+
+        let res = tester
+            .send_connected(DoipPayload::EntityStatusRequest(EntityStatusRequest {}))
+            .await;
+        assert!(matches!(
+            res,
+            Err(CodecError::IoError(err)) if err.kind() == io::ErrorKind::NotConnected
+        ));
+
+        tester.connect(entity_addr).await.unwrap();
+        assert_eq!(tester.peer_addr().unwrap(), entity_addr);
+
+        tester
+            .send_connected(DoipPayload::EntityStatusRequest(EntityStatusRequest {}))
+            .await
+            .unwrap();
+
+        let (req, from) = entity.recv().await.unwrap().unwrap();
+        assert_eq!(from, tester_addr);
+        assert_eq!(
+            req.payload,
+            DoipPayload::EntityStatusRequest(EntityStatusRequest {})
+        );
+
+        entity
+            .send(
+                DoipPayload::PowerInformationRequest(PowerInformationRequest {}),
+                tester_addr,
+            )
+            .await
+            .unwrap();
+
+        let res = tester.recv_connected().await.unwrap().unwrap();
+        assert_eq!(
+            res.payload,
+            DoipPayload::PowerInformationRequest(PowerInformationRequest {})
+        );
+    }
 }
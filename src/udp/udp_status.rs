@@ -0,0 +1,131 @@
+use std::{net::SocketAddr, time::Duration};
+
+use doip_definitions::payload::{
+    EntityStatusRequest, EntityStatusResponse, NodeType, PowerInformationRequest, PowerMode,
+};
+
+use crate::error::UdpRequestError;
+
+use super::UdpSocket;
+
+/// Time to wait for a response, A_DoIP_Ctrl of ISO 13400-2
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of times a request is repeated if no response arrives
+const RETRIES: u32 = 2;
+
+/// Status of a DoIP entity as reported in an Entity Status Response
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityStatus {
+    /// Whether the entity is a gateway or a node
+    pub node_type: NodeType,
+    /// Maximum number of concurrently open TCP sockets
+    pub max_concurrent_sockets: u8,
+    /// Number of currently open TCP sockets
+    pub currently_open_sockets: u8,
+    /// Maximum size of a Diagnostic Message the entity can process
+    pub max_data_size: u32,
+}
+
+impl EntityStatus {
+    /// Returns true if the entity accepts another TCP socket
+    pub fn has_free_socket(&self) -> bool {
+        self.currently_open_sockets < self.max_concurrent_sockets
+    }
+}
+
+impl From<EntityStatusResponse> for EntityStatus {
+    fn from(res: EntityStatusResponse) -> Self {
+        EntityStatus {
+            node_type: res.node_type,
+            max_concurrent_sockets: res.max_concurrent_sockets[0],
+            currently_open_sockets: res.currently_open_sockets[0],
+            max_data_size: u32::from_be_bytes(res.max_data_size),
+        }
+    }
+}
+
+impl UdpSocket {
+    /// Query the status of the DoIP entity at `addr`
+    ///
+    /// The `max_data_size` can be passed to `set_max_data_size` of a TCP
+    /// stream to the same entity to reject Diagnostic Messages it cannot
+    /// process before they are sent.
+    pub async fn entity_status(
+        &mut self,
+        addr: SocketAddr,
+    ) -> Result<EntityStatus, UdpRequestError> {
+        let res = self
+            .request(EntityStatusRequest {}, addr, RESPONSE_TIMEOUT, RETRIES)
+            .await?;
+
+        Ok(res.into())
+    }
+
+    /// Query the diagnostic power mode of the DoIP entity at `addr`
+    pub async fn power_mode(&mut self, addr: SocketAddr) -> Result<PowerMode, UdpRequestError> {
+        let res = self
+            .request(PowerInformationRequest {}, addr, RESPONSE_TIMEOUT, RETRIES)
+            .await?;
+
+        Ok(res.power_mode)
+    }
+}
+
+#[cfg(test)]
+mod test_udp_status {
+    use doip_definitions::payload::{
+        DoipPayload, EntityStatusResponse, NodeType, PowerInformationResponse, PowerMode,
+    };
+
+    use crate::udp::{udp_status::EntityStatus, UdpSocket};
+
+    #[tokio::test]
+    async fn test_entity_status_and_power_mode() {
+        const TESTER_ADDR: &str = "127.0.0.1:0";
+
+        let mut tester = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let mut entity = UdpSocket::bind(TESTER_ADDR).await.unwrap();
+        let entity_addr = entity.get_socket_ref().local_addr().unwrap();
+
+        let responder = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (req, tester) = entity.recv().await.unwrap().unwrap();
+                let res = match req.payload {
+                    DoipPayload::EntityStatusRequest(_) => {
+                        DoipPayload::EntityStatusResponse(EntityStatusResponse {
+                            node_type: NodeType::DoipGateway,
+                            max_concurrent_sockets: [2],
+                            currently_open_sockets: [1],
+                            max_data_size: [0x00, 0x00, 0x10, 0x00],
+                        })
+                    }
+                    DoipPayload::PowerInformationRequest(_) => {
+                        DoipPayload::PowerInformationResponse(PowerInformationResponse {
+                            power_mode: PowerMode::Ready,
+                        })
+                    }
+                    payload => panic!("unexpected request {:?}", payload),
+                };
+                entity.send(res, tester).await.unwrap();
+            }
+        });
+
+        let status = tester.entity_status(entity_addr).await.unwrap();
+        assert_eq!(
+            status,
+            EntityStatus {
+                node_type: NodeType::DoipGateway,
+                max_concurrent_sockets: 2,
+                currently_open_sockets: 1,
+                max_data_size: 4096,
+            }
+        );
+        assert!(status.has_free_socket());
+
+        let power_mode = tester.power_mode(entity_addr).await.unwrap();
+        assert_eq!(power_mode, PowerMode::Ready);
+
+        responder.await.unwrap();
+    }
+}